@@ -1,10 +1,25 @@
+use std::collections::BTreeMap;
 use std::sync::Arc;
-use rocket::{get, State};
+use chrono::{Duration, Utc};
+use rocket::{delete, get, post, State};
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::response::status::{BadRequest, Created, NoContent};
 use rocket::serde::json::Json;
-use serde::Serialize;
-use crate::lieferengpaesse::Lieferengpass;
+use serde::{Deserialize, Serialize};
+use crate::atc::AtcNode;
+use crate::caching::{Cached, Conditional};
+use crate::changes::{Change, ChangeSet, Dataset};
+use crate::history::LieferengpassHistory;
+use crate::lieferengpaesse::{Lieferengpass, RejectedRow};
 use crate::rote_hand_briefe::Brief;
-use crate::TempStorage;
+use crate::filter::{BriefFilter, LieferengpassFilter, QueryDate, QueryDateTime};
+use crate::pagination::{Page, Pagination};
+use crate::search::SearchHit;
+use crate::stats::{StatsInterval, Summary, Timeline};
+use crate::status::{Source, SourceStatus};
+use crate::webhooks::{self, Webhook, WebhookFilter};
+use crate::{persistence, TempStorage};
 
 #[derive(Serialize, Clone)]
 pub enum ApiResponse<T>{
@@ -12,23 +27,296 @@ pub enum ApiResponse<T>{
     Success(T),
 }
 
-#[get("/lieferengpaesse")]
-pub async fn lieferengpaesse(storage: &State<Arc<TempStorage>>) -> Json<ApiResponse<Vec<Lieferengpass>>> {
-    if !storage.storage.read().await.lieferengpaesse_loaded_initially{
-        return Json(ApiResponse::NotReady)
+#[get("/lieferengpaesse?<limit>&<offset>&<cursor>&<filter..>")]
+pub async fn lieferengpaesse(storage: &State<Arc<TempStorage>>, conditional: Conditional, limit: Option<usize>, offset: Option<usize>, cursor: Option<&str>, filter: LieferengpassFilter) -> Result<Cached<Json<ApiResponse<Page<Lieferengpass>>>>, BadRequest<&'static str>> {
+    let pagination = Pagination::from_query(limit, offset, cursor).map_err(BadRequest)?;
+
+    let handle = storage.storage.read().await;
+    if !handle.lieferengpaesse_loaded_initially{
+        return Ok(Cached::Fresh(Json(ApiResponse::NotReady), None))
+    }
+
+    Ok(conditional.respond(handle.lieferengpaesse_version, || {
+        let data = filter.apply(&handle.lieferengpaesse);
+        Json(ApiResponse::Success(pagination.paginate(data, handle.lieferengpaesse_refreshed_at)))
+    }))
+}
+
+/// Rows of the latest PharmNet CSV that could not be parsed.
+#[get("/lieferengpaesse/rejected")]
+pub async fn lieferengpaesse_rejected(storage: &State<Arc<TempStorage>>, conditional: Conditional) -> Cached<Json<ApiResponse<Vec<RejectedRow>>>> {
+    let handle = storage.storage.read().await;
+    let Some(rejected) = &handle.lieferengpaesse_rejected else {
+        return Cached::Fresh(Json(ApiResponse::NotReady), None)
+    };
+
+    conditional.respond(handle.lieferengpaesse_version, || Json(ApiResponse::Success(rejected.clone())))
+}
+
+#[get("/lieferengpaesse/<bearbeitungsnummer>")]
+pub async fn lieferengpass(storage: &State<Arc<TempStorage>>, conditional: Conditional, bearbeitungsnummer: &str) -> Cached<Option<Json<ApiResponse<Lieferengpass>>>> {
+    let handle = storage.storage.read().await;
+    if !handle.lieferengpaesse_loaded_initially{
+        return Cached::Fresh(Some(Json(ApiResponse::NotReady)), None)
+    }
+
+    conditional.respond(handle.lieferengpaesse_version, || {
+        handle.lieferengpaesse.iter()
+            .find(|entry| entry.bearbeitungsnummer == bearbeitungsnummer)
+            .map(|entry| Json(ApiResponse::Success(entry.clone())))
+    })
+}
+
+#[get("/lieferengpaesse/<bearbeitungsnummer>/history", rank = 2)]
+pub async fn lieferengpass_history(storage: &State<Arc<TempStorage>>, conditional: Conditional, bearbeitungsnummer: &str) -> Cached<Option<Json<ApiResponse<LieferengpassHistory>>>> {
+    let handle = storage.storage.read().await;
+    if !handle.lieferengpaesse_loaded_initially{
+        return Cached::Fresh(Some(Json(ApiResponse::NotReady)), None)
+    }
+
+    conditional.respond(handle.lieferengpaesse_version, || {
+        handle.lieferengpaesse_history.get(bearbeitungsnummer)
+            .map(|history| Json(ApiResponse::Success(history.clone())))
+    })
+}
+
+/// Returns the most recent report for the given PZN.
+#[get("/lieferengpaesse/pzn/<pzn>")]
+pub async fn lieferengpass_by_pzn(storage: &State<Arc<TempStorage>>, conditional: Conditional, pzn: usize) -> Cached<Option<Json<ApiResponse<Lieferengpass>>>> {
+    let handle = storage.storage.read().await;
+    if !handle.lieferengpaesse_loaded_initially{
+        return Cached::Fresh(Some(Json(ApiResponse::NotReady)), None)
+    }
+
+    conditional.respond(handle.lieferengpaesse_version, || {
+        handle.lieferengpaesse.iter()
+            .filter(|entry| entry.pzn == pzn)
+            .max_by_key(|entry| entry.letzte_meldung)
+            .map(|entry| Json(ApiResponse::Success(entry.clone())))
+    })
+}
+
+#[get("/briefe?<limit>&<offset>&<cursor>&<full_text>&<filter..>")]
+pub async fn briefe(storage: &State<Arc<TempStorage>>, conditional: Conditional, limit: Option<usize>, offset: Option<usize>, cursor: Option<&str>, full_text: Option<bool>, filter: BriefFilter) -> Result<Cached<Json<ApiResponse<Page<Brief>>>>, BadRequest<&'static str>> {
+    let pagination = Pagination::from_query(limit, offset, cursor).map_err(BadRequest)?;
+
+    let handle = storage.storage.read().await;
+    if !handle.briefe_loaded_initially{
+        return Ok(Cached::Fresh(Json(ApiResponse::NotReady), None))
+    }
+
+    Ok(conditional.respond(handle.briefe_version, || {
+        let data = filter.apply(handle.briefe.values());
+        let mut page = pagination.paginate(data, handle.briefe_refreshed_at);
+        // The texts are long, only return them when asked for
+        if !full_text.unwrap_or(false){
+            page.items.iter_mut().for_each(|brief| brief.full_text = None);
+        }
+        Json(ApiResponse::Success(page))
+    }))
+}
+
+#[get("/briefe/<id>")]
+pub async fn brief(storage: &State<Arc<TempStorage>>, conditional: Conditional, id: &str) -> Cached<Option<Json<ApiResponse<Brief>>>> {
+    let handle = storage.storage.read().await;
+    if !handle.briefe_loaded_initially{
+        return Cached::Fresh(Some(Json(ApiResponse::NotReady)), None)
+    }
+
+    conditional.respond(handle.briefe_version, || {
+        handle.briefe.values()
+            .find(|brief| brief.id == id)
+            .map(|brief| Json(ApiResponse::Success(brief.clone())))
+    })
+}
+
+/// Levels below a group returned by the ATC endpoints unless `depth` is given, enough for the whole tree.
+const DEFAULT_ATC_DEPTH: usize = 4;
+
+/// ATC groups of level 1 with their subgroups and counts.
+#[get("/atc?<depth>")]
+pub async fn atc_groups(storage: &State<Arc<TempStorage>>, conditional: Conditional, depth: Option<usize>) -> Cached<Json<ApiResponse<Vec<AtcNode>>>> {
+    let handle = storage.storage.read().await;
+    if !handle.lieferengpaesse_loaded_initially{
+        return Cached::Fresh(Json(ApiResponse::NotReady), None)
+    }
+
+    conditional.respond(handle.lieferengpaesse_version, || {
+        Json(ApiResponse::Success(crate::atc::roots(&handle, &storage.atc_names, depth.unwrap_or(DEFAULT_ATC_DEPTH))))
+    })
+}
+
+/// A single ATC group, e.g. `J01` or `J01CA`, with its subgroups and counts.
+#[get("/atc/<code>?<depth>")]
+pub async fn atc_group(storage: &State<Arc<TempStorage>>, conditional: Conditional, code: &str, depth: Option<usize>) -> Result<Cached<Option<Json<ApiResponse<AtcNode>>>>, BadRequest<&'static str>> {
+    let code = code.trim().to_uppercase();
+    if !crate::atc::is_valid(&code){
+        return Err(BadRequest("invalid ATC code"));
+    }
+
+    let handle = storage.storage.read().await;
+    if !handle.lieferengpaesse_loaded_initially{
+        return Ok(Cached::Fresh(Some(Json(ApiResponse::NotReady)), None))
+    }
+
+    Ok(conditional.respond(handle.lieferengpaesse_version, || {
+        crate::atc::subtree(&handle, &storage.atc_names, &code, depth.unwrap_or(DEFAULT_ATC_DEPTH)).map(|node| Json(ApiResponse::Success(node)))
+    }))
+}
+
+/// Aggregates over the active Lieferengpässe matching the filter. `top` limits the Zulassungsinhaber and Darreichungsformen listed.
+#[get("/stats/summary?<top>&<filter..>")]
+pub async fn stats_summary(storage: &State<Arc<TempStorage>>, conditional: Conditional, top: Option<usize>, filter: LieferengpassFilter) -> Cached<Json<ApiResponse<Summary>>> {
+    let handle = storage.storage.read().await;
+    if !handle.lieferengpaesse_loaded_initially{
+        return Cached::Fresh(Json(ApiResponse::NotReady), None)
+    }
+
+    conditional.respond(handle.lieferengpaesse_version, || {
+        Json(ApiResponse::Success(Summary{
+            data_timestamp: handle.lieferengpaesse_refreshed_at,
+            active: crate::stats::summary(&handle.lieferengpaesse, &filter, top),
+        }))
+    })
+}
+
+/// Aggregates per week or month from `from` (default one year ago) to `to` (default today).
+#[get("/stats/timeline?<interval>&<from>&<to>&<top>&<filter..>")]
+pub async fn stats_timeline(storage: &State<Arc<TempStorage>>, conditional: Conditional, interval: Option<StatsInterval>, from: Option<QueryDate>, to: Option<QueryDate>, top: Option<usize>, filter: LieferengpassFilter) -> Result<Cached<Json<ApiResponse<Timeline>>>, BadRequest<&'static str>> {
+    let interval = interval.unwrap_or_default();
+    let today = Utc::now().date_naive();
+    let to = to.map_or(today, |to| to.0);
+    let from = from.map_or(to - Duration::days(365), |from| from.0);
+    if from > to{
+        return Err(BadRequest("from is after to"));
+    }
+    let periods = crate::stats::periods(from, to, interval).ok_or(BadRequest("too many periods, narrow down from and to"))?;
+
+    let handle = storage.storage.read().await;
+    if !handle.lieferengpaesse_loaded_initially{
+        return Ok(Cached::Fresh(Json(ApiResponse::NotReady), None))
+    }
+
+    // The default range moves with the date
+    Ok(conditional.respond(handle.lieferengpaesse_version.map(|version| version.as_of(today)), || {
+        Json(ApiResponse::Success(Timeline{
+            interval,
+            data_timestamp: handle.lieferengpaesse_refreshed_at,
+            periods: crate::stats::timeline(&handle.lieferengpaesse, &filter, &periods, top),
+        }))
+    }))
+}
+
+/// Letters and Lieferengpässe matching the query, best match first.
+#[get("/search?<q>&<dataset>&<limit>&<offset>&<cursor>")]
+pub async fn search(storage: &State<Arc<TempStorage>>, conditional: Conditional, q: &str, dataset: Option<Dataset>, limit: Option<usize>, offset: Option<usize>, cursor: Option<&str>) -> Result<Cached<Json<ApiResponse<Page<SearchHit>>>>, BadRequest<&'static str>> {
+    let pagination = Pagination::from_query(limit, offset, cursor).map_err(BadRequest)?;
+    if !crate::search::is_searchable(q){
+        return Err(BadRequest("query contains no searchable words"));
+    }
+
+    let handle = storage.storage.read().await;
+    if !handle.lieferengpaesse_loaded_initially || !handle.briefe_loaded_initially{
+        return Ok(Cached::Fresh(Json(ApiResponse::NotReady), None))
+    }
+
+    Ok(conditional.respond(crate::caching::combined(&handle), || {
+        let hits = crate::search::search(&handle, q, dataset);
+        let data_timestamp = handle.lieferengpaesse_refreshed_at.max(handle.briefe_refreshed_at);
+        let page = pagination.paginate(hits, data_timestamp).map(|hits| crate::search::resolve(&handle, hits));
+        Json(ApiResponse::Success(page))
+    }))
+}
+
+/// Lieferengpässe and letters added, modified or removed after `since`.
+#[get("/changes?<since>&<full_text>")]
+pub async fn changes(storage: &State<Arc<TempStorage>>, conditional: Conditional, since: QueryDateTime, full_text: Option<bool>) -> Cached<Json<ApiResponse<ChangeSet>>> {
+    let handle = storage.storage.read().await;
+    if !handle.lieferengpaesse_loaded_initially || !handle.briefe_loaded_initially{
+        return Cached::Fresh(Json(ApiResponse::NotReady), None)
+    }
+
+    conditional.respond(crate::caching::combined(&handle), || {
+        let mut changes = crate::changes::since(&handle, since.0);
+        // The texts are long, only return them when asked for
+        if !full_text.unwrap_or(false){
+            changes.briefe = changes.briefe.into_iter().map(Change::without_full_text).collect();
+        }
+        Json(ApiResponse::Success(changes))
+    })
+}
+
+/// Outcome of the latest crawl of each source, for monitoring.
+#[get("/status")]
+pub async fn status(storage: &State<Arc<TempStorage>>) -> Json<ApiResponse<BTreeMap<Source, SourceStatus>>> {
+    Json(ApiResponse::Success(crate::status::overview(&*storage.storage.read().await)))
+}
+
+#[derive(Deserialize)]
+pub struct WebhookRequest{
+    pub url: String,
+    #[serde(default)]
+    pub filter: WebhookFilter,
+}
+
+#[derive(Serialize)]
+pub struct WebhookRegistration{
+    pub webhook: Webhook,
+    /// Key for verifying the `X-MediHelp-Signature` header, needed to view or delete the webhook. Only returned once.
+    pub secret: String,
+}
+
+/// Secret of a webhook, sent in the `X-Webhook-Secret` header.
+pub struct WebhookSecret(String);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for WebhookSecret {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match request.headers().get_one("X-Webhook-Secret"){
+            Some(secret) => Outcome::Success(WebhookSecret(secret.to_string())),
+            None => Outcome::Error((Status::Unauthorized, ())),
+        }
     }
-    let data = storage.storage.read().await.lieferengpaesse.clone();
+}
+
+#[post("/webhooks", data = "<request>")]
+pub async fn create_webhook(storage: &State<Arc<TempStorage>>, request: Json<WebhookRequest>) -> Result<Created<Json<ApiResponse<WebhookRegistration>>>, BadRequest<String>> {
+    let request = request.into_inner();
+    let url = match reqwest::Url::parse(&request.url){
+        Ok(url) if url.scheme() == "http" || url.scheme() == "https" => url,
+        Ok(url) => return Err(BadRequest(format!("unsupported scheme: {}", url.scheme()))),
+        Err(e) => return Err(BadRequest(format!("invalid url: {}", e))),
+    };
+    if !storage.config.webhook_allow_private_targets{
+        webhooks::check_target(&url).await.map_err(BadRequest)?;
+    }
+
+    let webhook = Webhook::new(request.url, request.filter);
+    storage.storage.write().await.webhooks.push(webhook.clone());
+    persistence::save_webhooks(storage.inner().clone()).await;
 
-    Json(ApiResponse::Success(data))
+    let location = format!("/api/webhooks/{}", webhook.id);
+    let secret = webhook.secret.clone();
+    Ok(Created::new(location).body(Json(ApiResponse::Success(WebhookRegistration{ webhook, secret }))))
 }
 
-#[get("/briefe")]
-pub async fn briefe(storage: &State<Arc<TempStorage>>) -> Json<ApiResponse<Vec<Brief>>> {
-    if !storage.storage.read().await.briefe_loaded_initially{
-        return Json(ApiResponse::NotReady)
+#[get("/webhooks/<id>")]
+pub async fn webhook(storage: &State<Arc<TempStorage>>, id: &str, secret: WebhookSecret) -> Option<Json<ApiResponse<Webhook>>> {
+    storage.storage.read().await.webhooks.iter()
+        .find(|webhook| webhook.id == id && webhook.has_secret(&secret.0))
+        .map(|webhook| Json(ApiResponse::Success(webhook.clone())))
+}
+
+#[delete("/webhooks/<id>")]
+pub async fn delete_webhook(storage: &State<Arc<TempStorage>>, id: &str, secret: WebhookSecret) -> Option<NoContent> {
+    {
+        let mut handle = storage.storage.write().await;
+        let position = handle.webhooks.iter().position(|webhook| webhook.id == id && webhook.has_secret(&secret.0))?;
+        handle.webhooks.remove(position);
     }
-    
-    let data = storage.storage.read().await.briefe.clone().into_values().collect::<Vec<Brief>>();
-    
-    Json(ApiResponse::Success(data))
-}
\ No newline at end of file
+    persistence::save_webhooks(storage.inner().clone()).await;
+
+    Some(NoContent)
+}
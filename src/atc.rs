@@ -0,0 +1,166 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use serde::Serialize;
+use crate::lieferengpaesse::LieferengpassStatus;
+use crate::InnerStorage;
+
+/// Code lengths of the five ATC levels, e.g. `N`, `N02`, `N02B`, `N02BE` and `N02BE01`.
+const LEVEL_LENGTHS: [usize; 5] = [1, 3, 4, 5, 7];
+
+/// Names of the groups of levels 1 and 2. Deeper levels are only named if `atc_names` is configured.
+const BUNDLED_NAMES: &str = include_str!("../data/atc_names.tsv");
+
+/// German names of the ATC groups by code.
+///
+/// The bundled table only covers levels 1 and 2, as the names of the deeper levels come from the ATC index of the
+/// WIdO, which isn't bundled. A complete table can be configured with `atc_names`.
+pub struct AtcNames(HashMap<String, String>);
+
+impl AtcNames{
+    /// Reads tab-separated lines of code and name. Further columns and lines starting with `#` are ignored.
+    pub fn parse(text: &str) -> Self{
+        let names = text.lines()
+            .filter(|line| !line.starts_with('#'))
+            .filter_map(|line| {
+                let mut columns = line.split('\t');
+                let code = columns.next()?.trim().to_uppercase();
+                let name = columns.next()?.trim();
+                (is_valid(&code) && !name.is_empty()).then(|| (code, name.to_string()))
+            })
+            .collect();
+        AtcNames(names)
+    }
+
+    pub fn bundled() -> Self{
+        Self::parse(BUNDLED_NAMES)
+    }
+
+    /// The bundled names, completed and overridden by the table at `path`. Only the bundled names are used if the
+    /// file can't be read.
+    pub fn load(path: Option<&str>) -> Self{
+        let mut names = Self::bundled();
+        let Some(path) = path else { return names };
+        match std::fs::read_to_string(path){
+            Ok(text) => names.0.extend(Self::parse(&text).0),
+            Err(e) => tracing::warn!(path, error = %e, "Failed to load ATC names, using the bundled ones"),
+        }
+        names
+    }
+
+    pub fn get(&self, code: &str) -> Option<&str>{
+        self.0.get(code).map(String::as_str)
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct AtcNode{
+    pub code: String,
+    pub level: usize,
+    pub name: Option<String>,
+    /// Active Lieferengpässe in this group and all its subgroups.
+    pub active_lieferengpaesse: usize,
+    /// Letters related to these Lieferengpässe.
+    pub briefe: usize,
+    pub children: Vec<AtcNode>,
+}
+
+/// Level of an ATC code or prefix, `None` if its length doesn't match a level.
+pub fn level(code: &str) -> Option<usize>{
+    LEVEL_LENGTHS.iter().position(|length| *length == code.len()).map(|index| index + 1)
+}
+
+/// Whether the prefix is a well-formed ATC code of any level.
+pub fn is_valid(code: &str) -> bool{
+    level(code).is_some()
+        && code.starts_with(|c: char| c.is_ascii_uppercase())
+        && code.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+#[derive(Default)]
+struct Group{
+    active_lieferengpaesse: usize,
+    briefe: BTreeSet<String>,
+}
+
+/// Counts the active Lieferengpässe and their letters for every group containing one of them.
+fn groups(storage: &InnerStorage) -> BTreeMap<String, Group>{
+    let mut groups: BTreeMap<String, Group> = BTreeMap::new();
+    for entry in storage.lieferengpaesse.iter().filter(|entry| entry.status == LieferengpassStatus::Active){
+        let code = entry.atc.trim().to_uppercase();
+        if !is_valid(&code){
+            continue;
+        }
+        for length in LEVEL_LENGTHS.iter().filter(|length| **length <= code.len()){
+            let group = groups.entry(code[..*length].to_string()).or_default();
+            group.active_lieferengpaesse += 1;
+            group.briefe.extend(entry.related_briefe.iter().cloned());
+        }
+    }
+    groups
+}
+
+fn node(code: &str, groups: &BTreeMap<String, Group>, names: &AtcNames, depth: usize) -> AtcNode{
+    let group = groups.get(code);
+    let level = level(code).unwrap_or_default();
+
+    let children = match LEVEL_LENGTHS.get(level){
+        Some(child_length) if depth > 0 => groups.range(code.to_string()..)
+            .take_while(|(child, _)| child.starts_with(code))
+            .filter(|(child, _)| child.len() == *child_length)
+            .map(|(child, _)| node(child, groups, names, depth - 1))
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    AtcNode{
+        code: code.to_string(),
+        level,
+        name: names.get(code).map(str::to_string),
+        active_lieferengpaesse: group.map_or(0, |group| group.active_lieferengpaesse),
+        briefe: group.map_or(0, |group| group.briefe.len()),
+        children,
+    }
+}
+
+/// The groups of level 1 with their subgroups down to `depth` levels below.
+pub fn roots(storage: &InnerStorage, names: &AtcNames, depth: usize) -> Vec<AtcNode>{
+    let groups = groups(storage);
+    groups.keys()
+        .filter(|code| code.len() == LEVEL_LENGTHS[0])
+        .map(|code| node(code, &groups, names, depth))
+        .collect()
+}
+
+/// The group with the given code and its subgroups down to `depth` levels below. `None` if the code is
+/// neither named nor used by an active Lieferengpass.
+pub fn subtree(storage: &InnerStorage, names: &AtcNames, code: &str, depth: usize) -> Option<AtcNode>{
+    let groups = groups(storage);
+    if !groups.contains_key(code) && names.get(code).is_none(){
+        return None;
+    }
+    Some(node(code, &groups, names, depth))
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn bundled_names_cover_the_first_two_levels_only(){
+        let names = AtcNames::bundled();
+        assert_eq!(names.get("N"), Some("Nervensystem"));
+        assert!(names.get("J01").is_some());
+        assert_eq!(names.get("J01C"), None);
+    }
+
+    #[test]
+    fn configured_names_complete_the_bundled_ones(){
+        let path = std::env::temp_dir().join(format!("atc_names_{}.tsv", std::process::id()));
+        std::fs::write(&path, "# Code, name and DDD\nJ01CA04\tAmoxicillin\t1.5 g\nj01c\tBeta-Lactam-Antibiotika, Penicilline\n\nkein Code\n").unwrap();
+        let names = AtcNames::load(path.to_str());
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(names.get("J01CA04"), Some("Amoxicillin"));
+        assert_eq!(names.get("J01C"), Some("Beta-Lactam-Antibiotika, Penicilline"));
+        assert_eq!(names.get("N"), Some("Nervensystem"));
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io;
+use chrono::{DateTime, NaiveDate, Utc};
+use rocket::http::{Header, Status};
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::response::{self, Responder, Response};
+use serde::Serialize;
+use crate::rote_hand_briefe::Brief;
+use crate::InnerStorage;
+
+/// Content version of a dataset. Only changes if the data does, not on every refresh.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DataVersion{
+    /// Hash of the serialized dataset.
+    pub fingerprint: u64,
+    /// When the fingerprint last changed, or the time of the refresh the data was loaded from.
+    pub last_modified: DateTime<Utc>,
+}
+
+impl DataVersion{
+    /// Version of a response built from two datasets.
+    pub fn and(self, other: DataVersion) -> DataVersion{
+        DataVersion{
+            fingerprint: fingerprint(&(self.fingerprint, other.fingerprint)),
+            last_modified: self.last_modified.max(other.last_modified),
+        }
+    }
+
+    /// Version of a response that also depends on the current date, like defaults relative to today.
+    pub fn as_of(self, date: NaiveDate) -> DataVersion{
+        let day_start = date.and_hms_opt(0, 0, 0).map_or(self.last_modified, |start| start.and_utc());
+        DataVersion{
+            fingerprint: fingerprint(&(self.fingerprint, date)),
+            last_modified: self.last_modified.max(day_start),
+        }
+    }
+
+    /// Weak, as the same data is returned with different `data_timestamp`s and encodings.
+    pub fn etag(&self) -> String{
+        format!("W/\"{:016x}\"", self.fingerprint)
+    }
+
+    fn headers(&self) -> [Header<'static>; 3]{
+        [
+            Header::new("ETag", self.etag()),
+            Header::new("Last-Modified", self.last_modified.format("%a, %d %b %Y %H:%M:%S GMT").to_string()),
+            // Without it browsers may reuse a response for a while based on Last-Modified alone
+            Header::new("Cache-Control", "no-cache"),
+        ]
+    }
+}
+
+fn fingerprint(value: &impl Hash) -> u64{
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Feeds serialized JSON into a hasher without building the whole string.
+struct HashWriter(DefaultHasher);
+
+impl io::Write for HashWriter{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize>{
+        self.0.write(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()>{
+        Ok(())
+    }
+}
+
+fn serialized_fingerprint(value: &impl Serialize) -> u64{
+    let mut writer = HashWriter(DefaultHasher::new());
+    if let Err(e) = serde_json::to_writer(&mut writer, value){
+        tracing::error!(error = %e, "Failed to fingerprint data");
+    }
+    writer.0.finish()
+}
+
+fn next_version(previous: Option<DataVersion>, fingerprint: u64, refreshed_at: Option<DateTime<Utc>>) -> DataVersion{
+    match previous{
+        Some(previous) if previous.fingerprint == fingerprint => previous,
+        Some(_) => DataVersion{ fingerprint, last_modified: Utc::now() },
+        None => DataVersion{ fingerprint, last_modified: refreshed_at.unwrap_or_else(Utc::now) },
+    }
+}
+
+/// Updates the versions of both datasets after a refresh.
+pub fn update(storage: &mut InnerStorage){
+    // The rejected rows come from the same CSV and share the version
+    let fingerprint = serialized_fingerprint(&(&storage.lieferengpaesse, &storage.lieferengpaesse_rejected));
+    storage.lieferengpaesse_version = Some(next_version(storage.lieferengpaesse_version, fingerprint, storage.lieferengpaesse_refreshed_at));
+
+    // Sorted, as the order of the map differs between runs
+    let mut briefe: Vec<&Brief> = storage.briefe.values().collect();
+    briefe.sort_by(|a, b| a.id.cmp(&b.id));
+    let fingerprint = serialized_fingerprint(&briefe);
+    storage.briefe_version = Some(next_version(storage.briefe_version, fingerprint, storage.briefe_refreshed_at));
+}
+
+/// Version of responses built from both datasets.
+pub fn combined(storage: &InnerStorage) -> Option<DataVersion>{
+    storage.lieferengpaesse_version.zip(storage.briefe_version).map(|(lieferengpaesse, briefe)| lieferengpaesse.and(briefe))
+}
+
+/// `If-None-Match` and `If-Modified-Since` headers of a request.
+pub struct Conditional{
+    if_none_match: Option<String>,
+    if_modified_since: Option<DateTime<Utc>>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Conditional {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let headers = request.headers();
+        Outcome::Success(Conditional{
+            if_none_match: headers.get_one("If-None-Match").map(str::to_string),
+            if_modified_since: headers.get_one("If-Modified-Since")
+                .and_then(|value| DateTime::parse_from_rfc2822(value).ok())
+                .map(|date_time| date_time.with_timezone(&Utc)),
+        })
+    }
+}
+
+impl Conditional{
+    /// Whether the client's copy is current. `If-Modified-Since` is ignored if `If-None-Match` is sent.
+    pub fn is_current(&self, version: &DataVersion) -> bool{
+        if let Some(if_none_match) = &self.if_none_match{
+            let etag = version.etag();
+            let opaque = etag.trim_start_matches("W/");
+            return if_none_match.split(',')
+                .map(str::trim)
+                .any(|tag| tag == "*" || tag.trim_start_matches("W/") == opaque);
+        }
+        // The header only has second precision
+        self.if_modified_since.is_some_and(|since| version.last_modified.timestamp() <= since.timestamp())
+    }
+
+    /// `304 Not Modified` if the client's copy is current, otherwise the response built by `body`.
+    pub fn respond<R>(&self, version: Option<DataVersion>, body: impl FnOnce() -> R) -> Cached<R>{
+        match version{
+            Some(version) if self.is_current(&version) => Cached::NotModified(version),
+            _ => Cached::Fresh(body(), version),
+        }
+    }
+}
+
+/// Response with `ETag` and `Last-Modified` headers, or an empty `304 Not Modified`.
+pub enum Cached<R>{
+    NotModified(DataVersion),
+    /// Headers are only added to successful responses with a version.
+    Fresh(R, Option<DataVersion>),
+}
+
+impl<'r, 'o: 'r, R: Responder<'r, 'o>> Responder<'r, 'o> for Cached<R> {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'o> {
+        let (mut response, version) = match self{
+            Cached::NotModified(version) => (Response::build().status(Status::NotModified).finalize(), version),
+            Cached::Fresh(body, None) => return body.respond_to(request),
+            Cached::Fresh(body, Some(version)) => (body.respond_to(request)?, version),
+        };
+
+        if response.status().class().is_success() || response.status() == Status::NotModified{
+            for header in version.headers(){
+                response.set_header(header);
+            }
+        }
+        Ok(response)
+    }
+}
@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use crate::InnerStorage;
+use crate::lieferengpaesse::Lieferengpass;
+use crate::rote_hand_briefe::Brief;
+
+/// How long change events are kept. Clients that polled longer ago have to fetch the full lists again.
+pub const CHANGE_RETENTION: Duration = Duration::days(30);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind{
+    Added,
+    Modified,
+    Removed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Dataset{
+    Lieferengpaesse,
+    Briefe,
+}
+
+/// A change noticed during a refresh. `id` is the Bearbeitungsnummer or the letter id.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChangeEvent{
+    pub at: DateTime<Utc>,
+    pub dataset: Dataset,
+    pub id: String,
+    pub kind: ChangeKind,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Change<T>{
+    pub id: String,
+    pub kind: ChangeKind,
+    pub changed_at: DateTime<Utc>,
+    /// Current state of the record. Removed shortages are returned with their last known state, removed letters without one.
+    pub record: Option<T>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ChangeSet{
+    pub since: DateTime<Utc>,
+    /// Pass as `since` on the next poll.
+    pub until: DateTime<Utc>,
+    /// `false` if `since` is older than the change log, the client has to fetch the full lists again.
+    pub complete: bool,
+    pub lieferengpaesse: Vec<Change<Lieferengpass>>,
+    pub briefe: Vec<Change<Brief>>,
+}
+
+impl Change<Brief>{
+    /// Drops the text extracted from the PDF, which is large and only returned on request.
+    pub fn without_full_text(mut self) -> Self{
+        if let Some(brief) = &mut self.record{
+            brief.full_text = None;
+        }
+        self
+    }
+}
+
+/// Appends events to the change log and drops the ones older than [`CHANGE_RETENTION`].
+pub fn log(storage: &mut InnerStorage, events: impl IntoIterator<Item = ChangeEvent>){
+    let now = Utc::now();
+    storage.changes.extend(events);
+    storage.changes.retain(|event| event.at >= now - CHANGE_RETENTION);
+
+    let log_start = storage.changes_log_start.get_or_insert(now);
+    if *log_start < now - CHANGE_RETENTION{
+        *log_start = now - CHANGE_RETENTION;
+    }
+}
+
+/// Collects the changes after `since`. Multiple changes of the same record are merged into the latest one.
+pub fn since(storage: &InnerStorage, since: DateTime<Utc>) -> ChangeSet{
+    let mut latest: HashMap<(Dataset, &str), &ChangeEvent> = HashMap::new();
+    for event in storage.changes.iter().filter(|event| event.at > since){
+        latest.insert((event.dataset, event.id.as_str()), event);
+    }
+
+    let lieferengpaesse_by_id: HashMap<&str, &Lieferengpass> = storage.lieferengpaesse.iter().map(|entry| (entry.bearbeitungsnummer.as_str(), entry)).collect();
+    let briefe_by_id: HashMap<&str, &Brief> = storage.briefe.values().map(|brief| (brief.id.as_str(), brief)).collect();
+
+    let mut events: Vec<&ChangeEvent> = latest.into_values().collect();
+    events.sort_by(|a, b| a.at.cmp(&b.at).then_with(|| a.id.cmp(&b.id)));
+
+    let mut lieferengpaesse = Vec::new();
+    let mut briefe = Vec::new();
+    for event in events{
+        match event.dataset{
+            Dataset::Lieferengpaesse => lieferengpaesse.push(Change{
+                id: event.id.clone(),
+                kind: event.kind,
+                changed_at: event.at,
+                record: lieferengpaesse_by_id.get(event.id.as_str()).map(|entry| (*entry).clone()),
+            }),
+            Dataset::Briefe => briefe.push(Change{
+                id: event.id.clone(),
+                kind: event.kind,
+                changed_at: event.at,
+                record: briefe_by_id.get(event.id.as_str()).map(|brief| (*brief).clone()),
+            }),
+        }
+    }
+
+    let until = storage.changes.iter().map(|event| event.at).max().unwrap_or(since).max(since);
+
+    ChangeSet{
+        since,
+        until,
+        complete: storage.changes_log_start.is_some_and(|log_start| log_start <= since),
+        lieferengpaesse,
+        briefe,
+    }
+}
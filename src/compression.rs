@@ -0,0 +1,192 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::io::{Cursor, Write};
+use std::sync::{Arc, Mutex};
+use flate2::write::GzEncoder;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{ContentType, Header};
+use rocket::{Request, Response};
+
+/// Smaller bodies are sent as they are, compressing them saves next to nothing.
+const MIN_COMPRESSED_SIZE: usize = 1024;
+/// Compressed bodies kept for reuse, the oldest are dropped first.
+const MAX_CACHE_ENTRIES: usize = 256;
+/// Brotli quality 0-11, higher levels take much longer for little gain on JSON.
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW: u32 = 22;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Encoding{
+    Brotli,
+    Gzip,
+}
+
+impl Encoding{
+    fn name(self) -> &'static str{
+        match self{
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    fn compress(self, body: &[u8]) -> std::io::Result<Vec<u8>>{
+        match self{
+            Encoding::Brotli => {
+                let mut writer = brotli::CompressorWriter::new(Vec::new(), 4096, BROTLI_QUALITY, BROTLI_WINDOW);
+                writer.write_all(body)?;
+                Ok(writer.into_inner())
+            }
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// Picks the encoding with the highest `q` value from `Accept-Encoding` headers, Brotli if both are equal.
+pub fn negotiate<'a>(accept_encoding: impl Iterator<Item = &'a str>) -> Option<Encoding>{
+    let mut quality: HashMap<String, f32> = HashMap::new();
+    for item in accept_encoding.flat_map(|header| header.split(',')){
+        let mut parts = item.split(';').map(str::trim);
+        let name = parts.next().unwrap_or_default().to_lowercase();
+        let q = parts.filter_map(|parameter| parameter.strip_prefix("q="))
+            .find_map(|value| value.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if !name.is_empty(){
+            quality.insert(name, q);
+        }
+    }
+
+    let wildcard = quality.get("*").copied().unwrap_or(0.0);
+    [Encoding::Brotli, Encoding::Gzip].into_iter()
+        .map(|encoding| (encoding, quality.get(encoding.name()).copied().unwrap_or(wildcard)))
+        .filter(|(_, q)| *q > 0.0)
+        .fold(None, |best: Option<(Encoding, f32)>, (encoding, q)| match best{
+            Some((_, best_q)) if best_q >= q => best,
+            _ => Some((encoding, q)),
+        })
+        .map(|(encoding, _)| encoding)
+}
+
+/// JSON, CSV, XML feeds and other text. Excel files are zip archives already.
+fn is_compressible(content_type: &ContentType) -> bool{
+    content_type.is_json() || content_type.top() == "text" || content_type.sub().as_str().ends_with("xml")
+}
+
+fn body_hash(body: &[u8]) -> u64{
+    let mut hasher = DefaultHasher::new();
+    body.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[derive(PartialEq, Eq, Hash, Clone)]
+struct CacheKey{
+    uri: String,
+    etag: String,
+    encoding: Encoding,
+}
+
+struct CachedBody{
+    /// Hash of the uncompressed body. The same version can be returned with a different `data_timestamp`.
+    body_hash: u64,
+    compressed: Arc<[u8]>,
+}
+
+#[derive(Default)]
+struct Cache{
+    entries: HashMap<CacheKey, CachedBody>,
+    /// Keys in the order they were added.
+    order: VecDeque<CacheKey>,
+}
+
+impl Cache{
+    fn get(&self, key: &CacheKey, body_hash: u64) -> Option<Arc<[u8]>>{
+        self.entries.get(key)
+            .filter(|cached| cached.body_hash == body_hash)
+            .map(|cached| cached.compressed.clone())
+    }
+
+    fn insert(&mut self, key: CacheKey, cached: CachedBody){
+        if self.entries.insert(key.clone(), cached).is_none(){
+            self.order.push_back(key);
+        }
+        while self.order.len() > MAX_CACHE_ENTRIES{
+            if let Some(oldest) = self.order.pop_front(){
+                self.entries.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// Compresses responses with gzip or Brotli as accepted by the client. Compressed bodies of responses
+/// with an `ETag` are cached per URL and data version, so they are only compressed once.
+#[derive(Default)]
+pub struct Compression{
+    cache: Mutex<Cache>,
+}
+
+#[rocket::async_trait]
+impl Fairing for Compression {
+    fn info(&self) -> Info {
+        Info{ name: "Response compression", kind: Kind::Response }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        if !response.content_type().is_some_and(|content_type| is_compressible(&content_type))
+            || response.headers().contains("Content-Encoding"){
+            return;
+        }
+        response.set_header(Header::new("Vary", "Accept-Encoding"));
+
+        let Some(encoding) = negotiate(request.headers().get("Accept-Encoding")) else { return };
+        let body = match response.body_mut().to_bytes().await{
+            Ok(body) => body,
+            Err(e) => {
+                tracing::warn!(error = %e, "Failed to read response body for compression");
+                return;
+            }
+        };
+        if body.len() < MIN_COMPRESSED_SIZE{
+            response.set_sized_body(body.len(), Cursor::new(body));
+            return;
+        }
+
+        let key = response.headers().get_one("ETag").map(|etag| CacheKey{
+            uri: request.uri().to_string(),
+            etag: etag.to_string(),
+            encoding,
+        });
+        let hash = body_hash(&body);
+        let cached = key.as_ref().and_then(|key| self.cache.lock().unwrap().get(key, hash));
+
+        let compressed = match cached{
+            Some(compressed) => compressed,
+            None => {
+                let body: Arc<[u8]> = body.into();
+                let uncompressed = body.clone();
+                let result = rocket::tokio::task::spawn_blocking(move || encoding.compress(&uncompressed))
+                    .await
+                    .map_err(|e| e.to_string())
+                    .and_then(|result| result.map_err(|e| e.to_string()));
+                let compressed: Arc<[u8]> = match result{
+                    Ok(compressed) => compressed.into(),
+                    Err(e) => {
+                        tracing::warn!(error = %e, "Failed to compress response");
+                        response.set_sized_body(body.len(), Cursor::new(body));
+                        return;
+                    }
+                };
+                if let Some(key) = key{
+                    self.cache.lock().unwrap().insert(key, CachedBody{ body_hash: hash, compressed: compressed.clone() });
+                }
+                compressed
+            }
+        };
+
+        response.set_header(Header::new("Content-Encoding", encoding.name()));
+        response.set_sized_body(compressed.len(), Cursor::new(compressed));
+    }
+}
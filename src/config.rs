@@ -0,0 +1,108 @@
+use serde::Deserialize;
+
+/// Application settings, read from `Rocket.toml` or `ROCKET_*` environment variables next to Rocket's own config.
+#[derive(Deserialize, Debug, Clone)]
+pub struct AppConfig{
+    #[serde(default)]
+    pub persistence: PersistenceKind,
+    /// Path of the SQLite database, only used with `persistence = "sqlite"`.
+    #[serde(default = "default_database")]
+    pub database: String,
+    /// Number of delivery attempts per webhook call.
+    #[serde(default = "default_webhook_max_attempts")]
+    pub webhook_max_attempts: u32,
+    /// Wait time after the first failed delivery, doubled after each further attempt.
+    #[serde(default = "default_webhook_backoff_seconds")]
+    pub webhook_backoff_seconds: u64,
+    /// Allows webhooks to loopback, private and link-local addresses, e.g. for a receiver on the same host. Off by
+    /// default, as anyone can register a webhook and would otherwise reach internal services through this server.
+    #[serde(default)]
+    pub webhook_allow_private_targets: bool,
+    /// Path of a Wirkstoff dictionary for PEI letters, replaces the bundled `data/pei_wirkstoffe.txt`.
+    #[serde(default)]
+    pub pei_wirkstoffe: Option<String>,
+    /// Path of a tab-separated table of ATC codes and names, e.g. from the WIdO ATC index. Completes the bundled
+    /// `data/atc_names.tsv`, which only covers levels 1 and 2.
+    #[serde(default)]
+    pub atc_names: Option<String>,
+    /// Number of already known PEI letters whose page is crawled again per refresh, in turns, to notice changes. The
+    /// search results only have the title, new letters are always crawled.
+    #[serde(default = "default_pei_recheck_letters")]
+    pub pei_recheck_letters: usize,
+    /// Base URLs of the crawled sites, without trailing slash. Tests point them at local fixtures.
+    #[serde(default = "default_bfarm_base_url")]
+    pub bfarm_base_url: String,
+    #[serde(default = "default_pei_base_url")]
+    pub pei_base_url: String,
+    #[serde(default = "default_pharmnet_base_url")]
+    pub pharmnet_base_url: String,
+    /// Whether shortages with values unknown to the enums are rejected or kept with the value as `Other`.
+    #[serde(default)]
+    pub lieferengpaesse_parse_mode: ParseMode,
+    /// Days a shortage missing from the PharmNet CSV is kept as vanished before it is dropped. Its history is kept.
+    #[serde(default = "default_vanished_retention_days")]
+    pub vanished_retention_days: i64,
+    /// Format of the application logs. Rocket's own request log is not affected.
+    #[serde(default)]
+    pub log_format: LogFormat,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PersistenceKind{
+    #[default]
+    Memory,
+    Sqlite,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ParseMode{
+    /// Rows with unknown values end up in `/api/lieferengpaesse/rejected`.
+    #[default]
+    Strict,
+    /// Unknown values are kept as `Other`, the row is only rejected for other errors.
+    Lenient,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat{
+    /// Human-readable lines.
+    #[default]
+    Text,
+    /// One JSON object per event, including the fields of the enclosing spans.
+    Json,
+}
+
+fn default_database() -> String{
+    "medihelp.sqlite".to_string()
+}
+
+fn default_webhook_max_attempts() -> u32{
+    5
+}
+
+fn default_webhook_backoff_seconds() -> u64{
+    30
+}
+
+fn default_pei_recheck_letters() -> usize{
+    10
+}
+
+fn default_vanished_retention_days() -> i64{
+    30
+}
+
+fn default_bfarm_base_url() -> String{
+    "https://www.bfarm.de".to_string()
+}
+
+fn default_pei_base_url() -> String{
+    "https://www.pei.de".to_string()
+}
+
+fn default_pharmnet_base_url() -> String{
+    "https://anwendungen.pharmnet-bund.de".to_string()
+}
@@ -0,0 +1,249 @@
+use std::sync::Arc;
+use chrono::{DateTime, NaiveDate, Utc};
+use rocket::http::{ContentType, Header, Status};
+use rocket::{get, Responder, State};
+use rust_xlsxwriter::{Format, Workbook, XlsxError};
+use serde::{Deserialize, Serialize};
+use crate::caching::{Cached, Conditional};
+use crate::filter::LieferengpassFilter;
+use crate::lieferengpaesse::Lieferengpass;
+use crate::TempStorage;
+
+/// Column names of the exports, the field names of [`Lieferengpass`].
+pub const COLUMNS: [&str; 26] = [
+    "pzn",
+    "enr",
+    "bearbeitungsnummer",
+    "erstmeldung",
+    "erstmeldung_datum",
+    "meldungsart",
+    "beginn",
+    "ende",
+    "letzte_meldung",
+    "art_des_grundes",
+    "arzneimittelbezeichnung",
+    "atc",
+    "wirkstoffe",
+    "wirkstoffe_normalized",
+    "kkh_relevant",
+    "zulassungsinhaber",
+    "grund",
+    "anmerkung_zum_grund",
+    "alternativpraeparat",
+    "info_an_fachkreise",
+    "darreichungsform",
+    "klassifikation",
+    "status",
+    "vanished_at",
+    "folgemeldungen",
+    "related_briefe",
+];
+
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CsvDelimiter{
+    /// Default of Excel with German locale.
+    #[default]
+    Semicolon,
+    Comma,
+    Tab,
+}
+
+impl CsvDelimiter{
+    fn byte(self) -> u8{
+        match self{
+            CsvDelimiter::Semicolon => b';',
+            CsvDelimiter::Comma => b',',
+            CsvDelimiter::Tab => b'\t',
+        }
+    }
+}
+
+/// A single exported value, typed so the XLSX export can write numbers and dates as such.
+enum Cell{
+    Empty,
+    Text(String),
+    Number(f64),
+    Bool(bool),
+    Date(NaiveDate),
+    DateTime(DateTime<Utc>),
+}
+
+impl Cell{
+    fn text(value: impl Into<String>) -> Self{
+        Cell::Text(value.into())
+    }
+
+    fn optional(value: &Option<String>) -> Self{
+        value.as_ref().map_or(Cell::Empty, |value| Cell::text(value.as_str()))
+    }
+
+    /// Enums are exported with their serde name, like in the JSON output.
+    fn serde<T: Serialize>(value: &T) -> Self{
+        match serde_json::to_value(value){
+            Ok(serde_json::Value::String(text)) => Cell::Text(text),
+            Ok(other) => Cell::Text(other.to_string()),
+            Err(_) => Cell::Empty,
+        }
+    }
+
+    fn to_csv(&self) -> String{
+        match self{
+            Cell::Empty => String::new(),
+            Cell::Text(text) => text.clone(),
+            Cell::Number(number) => number.to_string(),
+            Cell::Bool(value) => value.to_string(),
+            Cell::Date(date) => date.format("%Y-%m-%d").to_string(),
+            Cell::DateTime(date_time) => date_time.to_rfc3339(),
+        }
+    }
+}
+
+fn join<T: ToString>(values: &[T]) -> String{
+    values.iter().map(|value| value.to_string()).collect::<Vec<_>>().join(", ")
+}
+
+/// Values of an entry in the order of [`COLUMNS`].
+fn row(entry: &Lieferengpass) -> [Cell; COLUMNS.len()]{
+    [
+        Cell::Number(entry.pzn as f64),
+        Cell::text(join(&entry.enr)),
+        Cell::text(entry.bearbeitungsnummer.as_str()),
+        Cell::optional(&entry.erstmeldung),
+        Cell::Date(entry.erstmeldung_datum),
+        Cell::serde(&entry.meldungsart),
+        Cell::Date(entry.beginn),
+        Cell::Date(entry.ende),
+        Cell::Date(entry.letzte_meldung),
+        Cell::serde(&entry.art_des_grundes),
+        Cell::text(entry.arzneimittelbezeichnung.as_str()),
+        Cell::text(entry.atc.as_str()),
+        Cell::text(entry.wirkstoffe.as_str()),
+        Cell::text(join(&entry.wirkstoffe_normalized)),
+        Cell::Bool(entry.kkh_relevant),
+        Cell::text(entry.zulassungsinhaber.as_str()),
+        Cell::text(entry.grund.as_str()),
+        Cell::optional(&entry.anmerkung_zum_grund),
+        Cell::optional(&entry.alternativpraeparat),
+        Cell::serde(&entry.info_an_fachkreise),
+        Cell::text(entry.darreichungsform.as_str()),
+        Cell::serde(&entry.klassifikation),
+        Cell::serde(&entry.status),
+        entry.vanished_at.map_or(Cell::Empty, Cell::DateTime),
+        Cell::text(join(&entry.folgemeldungen)),
+        Cell::text(join(&entry.related_briefe)),
+    ]
+}
+
+/// CSV with a header row, prefixed with a UTF-8 BOM so Excel detects the encoding.
+pub fn to_csv(entries: &[Lieferengpass], delimiter: CsvDelimiter) -> Result<Vec<u8>, csv::Error>{
+    let mut writer = csv::WriterBuilder::new()
+        .delimiter(delimiter.byte())
+        .from_writer(UTF8_BOM.to_vec());
+
+    writer.write_record(COLUMNS)?;
+    for entry in entries{
+        writer.write_record(row(entry).iter().map(Cell::to_csv))?;
+    }
+
+    writer.into_inner().map_err(|e| e.into_error().into())
+}
+
+/// Single worksheet with a frozen, filterable header row.
+pub fn to_xlsx(entries: &[Lieferengpass]) -> Result<Vec<u8>, XlsxError>{
+    let header_format = Format::new().set_bold();
+    let date_format = Format::new().set_num_format("dd.mm.yyyy");
+    let date_time_format = Format::new().set_num_format("dd.mm.yyyy hh:mm:ss");
+
+    let mut workbook = Workbook::new();
+    let worksheet = workbook.add_worksheet();
+    worksheet.set_name("Lieferengpässe")?;
+
+    for (column, name) in COLUMNS.iter().enumerate(){
+        worksheet.write_string_with_format(0, column as u16, *name, &header_format)?;
+    }
+
+    for (index, entry) in entries.iter().enumerate(){
+        let line = index as u32 + 1;
+        for (column, cell) in row(entry).iter().enumerate(){
+            let column = column as u16;
+            match cell{
+                Cell::Empty => {},
+                Cell::Text(text) => { worksheet.write_string(line, column, text)?; },
+                Cell::Number(number) => { worksheet.write_number(line, column, *number)?; },
+                Cell::Bool(value) => { worksheet.write_boolean(line, column, *value)?; },
+                Cell::Date(date) => { worksheet.write_datetime_with_format(line, column, date, &date_format)?; },
+                Cell::DateTime(date_time) => { worksheet.write_datetime_with_format(line, column, date_time.naive_utc(), &date_time_format)?; },
+            }
+        }
+    }
+
+    worksheet.set_freeze_panes(1, 0)?;
+    worksheet.autofilter(0, 0, entries.len() as u32, COLUMNS.len() as u16 - 1)?;
+    worksheet.autofit();
+
+    workbook.save_to_buffer()
+}
+
+#[derive(Responder)]
+pub struct Download{
+    body: Vec<u8>,
+    content_type: ContentType,
+    disposition: Header<'static>,
+}
+
+impl Download{
+    fn new(body: Vec<u8>, content_type: ContentType, file_name: &str) -> Self{
+        Download{
+            body,
+            content_type,
+            disposition: Header::new("Content-Disposition", format!("attachment; filename=\"{}\"", file_name)),
+        }
+    }
+}
+
+/// The matching shortages, or `304 Not Modified` if the client's copy is current.
+async fn export_lieferengpaesse(storage: &TempStorage, conditional: &Conditional, filter: &LieferengpassFilter) -> Result<Cached<Vec<Lieferengpass>>, Status>{
+    let handle = storage.storage.read().await;
+    if !handle.lieferengpaesse_loaded_initially{
+        return Err(Status::ServiceUnavailable);
+    }
+
+    Ok(conditional.respond(handle.lieferengpaesse_version, || filter.apply(&handle.lieferengpaesse)))
+}
+
+/// All shortages matching the filters of `/api/lieferengpaesse` as CSV.
+#[get("/lieferengpaesse.csv?<delimiter>&<filter..>")]
+pub async fn lieferengpaesse_csv(storage: &State<Arc<TempStorage>>, conditional: Conditional, delimiter: Option<CsvDelimiter>, filter: LieferengpassFilter) -> Result<Cached<Download>, Status> {
+    let (entries, version) = match export_lieferengpaesse(storage, &conditional, &filter).await?{
+        Cached::NotModified(version) => return Ok(Cached::NotModified(version)),
+        Cached::Fresh(entries, version) => (entries, version),
+    };
+    let body = to_csv(&entries, delimiter.unwrap_or_default()).map_err(|e| {
+        tracing::error!(error = %e, "Failed to export Lieferengpässe as CSV");
+        Status::InternalServerError
+    })?;
+
+    Ok(Cached::Fresh(Download::new(body, ContentType::new("text", "csv").with_params(("charset", "utf-8")), "lieferengpaesse.csv"), version))
+}
+
+/// All shortages matching the filters of `/api/lieferengpaesse` as Excel workbook.
+#[get("/lieferengpaesse.xlsx?<filter..>")]
+pub async fn lieferengpaesse_xlsx(storage: &State<Arc<TempStorage>>, conditional: Conditional, filter: LieferengpassFilter) -> Result<Cached<Download>, Status> {
+    let (entries, version) = match export_lieferengpaesse(storage, &conditional, &filter).await?{
+        Cached::NotModified(version) => return Ok(Cached::NotModified(version)),
+        Cached::Fresh(entries, version) => (entries, version),
+    };
+    let body = rocket::tokio::task::spawn_blocking(move || to_xlsx(&entries))
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|result| result.map_err(|e| e.to_string()))
+        .map_err(|e| {
+            tracing::error!(error = %e, "Failed to export Lieferengpässe as XLSX");
+            Status::InternalServerError
+        })?;
+
+    Ok(Cached::Fresh(Download::new(body, ContentType::new("application", "vnd.openxmlformats-officedocument.spreadsheetml.sheet"), "lieferengpaesse.xlsx"), version))
+}
@@ -0,0 +1,142 @@
+use std::sync::Arc;
+use atom_syndication::{Category, Entry, Feed, Link, Text};
+use chrono::{DateTime, FixedOffset, NaiveDate, Utc};
+use rocket::http::{ContentType, Status};
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::{get, State};
+use rss::{Channel, Enclosure, Guid, Item};
+use crate::caching::{Cached, Conditional};
+use crate::filter::BriefFilter;
+use crate::rote_hand_briefe::{Brief, LetterSource, LetterType};
+use crate::TempStorage;
+
+const FEED_TITLE: &str = "Rote-Hand-Briefe und Informationsbriefe";
+const FEED_DESCRIPTION: &str = "Rote-Hand-Briefe und Informationsbriefe von BfArM und PEI";
+const DEFAULT_FEED_LIMIT: usize = 50;
+
+/// Scheme and host the request was sent to, used for the feeds' self links.
+pub struct BaseUrl(String);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for BaseUrl {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let scheme = request.headers().get_one("X-Forwarded-Proto").unwrap_or("http");
+        let host = request.host().map(|host| host.to_string()).unwrap_or_else(|| "localhost".to_string());
+        Outcome::Success(BaseUrl(format!("{}://{}", scheme, host)))
+    }
+}
+
+fn date_time(date: NaiveDate) -> DateTime<FixedOffset>{
+    date.and_hms_opt(0, 0, 0).unwrap_or_default().and_utc().fixed_offset()
+}
+
+fn source_name(source: &LetterSource) -> &'static str{
+    match source{
+        LetterSource::BfArM => "BfArM",
+        LetterSource::PEI => "PEI",
+    }
+}
+
+fn letter_type_name(letter_type: &LetterType) -> &'static str{
+    match letter_type{
+        LetterType::RoteHandBrief => "Rote-Hand-Brief",
+        LetterType::Informationsbrief => "Informationsbrief",
+    }
+}
+
+/// The newest letters and the time of the last refresh, or `304 Not Modified` if the client's copy is current.
+async fn feed_briefe(storage: &TempStorage, conditional: &Conditional, filter: &BriefFilter, limit: Option<usize>) -> Result<Cached<(Vec<Brief>, Option<DateTime<Utc>>)>, Status>{
+    let handle = storage.storage.read().await;
+    if !handle.briefe_loaded_initially{
+        return Err(Status::ServiceUnavailable);
+    }
+
+    Ok(conditional.respond(handle.briefe_version, || {
+        let mut briefe = filter.apply(handle.briefe.values());
+        briefe.truncate(limit.unwrap_or(DEFAULT_FEED_LIMIT));
+        (briefe, handle.briefe_refreshed_at)
+    }))
+}
+
+fn atom_entry(brief: &Brief) -> Entry{
+    let date = date_time(brief.date);
+
+    Entry{
+        title: Text::plain(brief.title.clone()),
+        id: brief.link_to_html.clone(),
+        updated: date,
+        published: Some(date),
+        summary: brief.short_description.clone().map(Text::plain),
+        categories: vec![
+            Category{ term: source_name(&brief.source).to_string(), ..Default::default() },
+            Category{ term: letter_type_name(&brief.letter_type).to_string(), ..Default::default() },
+        ],
+        links: vec![
+            Link{ href: brief.link_to_html.clone(), ..Default::default() },
+            Link{ href: brief.link_to_pdf.clone(), rel: "enclosure".to_string(), mime_type: Some("application/pdf".to_string()), ..Default::default() },
+        ],
+        ..Default::default()
+    }
+}
+
+fn rss_item(brief: &Brief) -> Item{
+    Item{
+        title: Some(brief.title.clone()),
+        link: Some(brief.link_to_html.clone()),
+        description: brief.short_description.clone(),
+        guid: Some(Guid{ value: brief.link_to_html.clone(), permalink: true }),
+        pub_date: Some(date_time(brief.date).to_rfc2822()),
+        categories: vec![
+            rss::Category{ name: source_name(&brief.source).to_string(), domain: None },
+            rss::Category{ name: letter_type_name(&brief.letter_type).to_string(), domain: None },
+        ],
+        // The size of the PDF is unknown without downloading it
+        enclosure: Some(Enclosure{ url: brief.link_to_pdf.clone(), length: "0".to_string(), mime_type: "application/pdf".to_string() }),
+        ..Default::default()
+    }
+}
+
+/// Newest letters as Atom feed, accepts the filters of `/api/briefe`.
+#[get("/briefe.atom?<limit>&<filter..>")]
+pub async fn briefe_atom(storage: &State<Arc<TempStorage>>, conditional: Conditional, base_url: BaseUrl, limit: Option<usize>, filter: BriefFilter) -> Result<Cached<(ContentType, String)>, Status> {
+    let ((briefe, refreshed_at), version) = match feed_briefe(storage, &conditional, &filter, limit).await?{
+        Cached::NotModified(version) => return Ok(Cached::NotModified(version)),
+        Cached::Fresh(feed, version) => (feed, version),
+    };
+    let self_link = format!("{}/feeds/briefe.atom", base_url.0);
+
+    let feed = Feed{
+        title: Text::plain(FEED_TITLE),
+        subtitle: Some(Text::plain(FEED_DESCRIPTION)),
+        id: self_link.clone(),
+        updated: refreshed_at.unwrap_or_else(Utc::now).fixed_offset(),
+        links: vec![Link{ href: self_link, rel: "self".to_string(), ..Default::default() }],
+        entries: briefe.iter().map(atom_entry).collect(),
+        ..Default::default()
+    };
+
+    Ok(Cached::Fresh((ContentType::new("application", "atom+xml"), feed.to_string()), version))
+}
+
+/// Newest letters as RSS 2.0 feed, accepts the filters of `/api/briefe`.
+#[get("/briefe.rss?<limit>&<filter..>")]
+pub async fn briefe_rss(storage: &State<Arc<TempStorage>>, conditional: Conditional, base_url: BaseUrl, limit: Option<usize>, filter: BriefFilter) -> Result<Cached<(ContentType, String)>, Status> {
+    let ((briefe, refreshed_at), version) = match feed_briefe(storage, &conditional, &filter, limit).await?{
+        Cached::NotModified(version) => return Ok(Cached::NotModified(version)),
+        Cached::Fresh(feed, version) => (feed, version),
+    };
+
+    let channel = Channel{
+        title: FEED_TITLE.to_string(),
+        link: format!("{}/feeds/briefe.rss", base_url.0),
+        description: FEED_DESCRIPTION.to_string(),
+        language: Some("de".to_string()),
+        last_build_date: refreshed_at.map(|refreshed_at| refreshed_at.to_rfc2822()),
+        items: briefe.iter().map(rss_item).collect(),
+        ..Default::default()
+    };
+
+    Ok(Cached::Fresh((ContentType::new("application", "rss+xml"), channel.to_string()), version))
+}
@@ -0,0 +1,214 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use rocket::form::{self, FromFormField, ValueField};
+use rocket::FromForm;
+use serde::{Deserialize, Serialize};
+use crate::changes::Dataset;
+use crate::export::CsvDelimiter;
+use crate::lieferengpaesse::{Klassifikation, Lieferengpass, LieferengpassStatus, Meldungsart};
+use crate::rote_hand_briefe::{Brief, LetterSource, LetterType};
+use crate::stats::StatsInterval;
+
+/// Implements `FromFormField` for enums by reusing their serde names, so query values match the JSON output.
+macro_rules! form_field_via_serde {
+    ($($ty:ty),*) => {$(
+        impl<'v> FromFormField<'v> for $ty {
+            fn from_value(field: ValueField<'v>) -> form::Result<'v, Self> {
+                serde_json::from_value(serde_json::Value::String(field.value.to_string()))
+                    .map_err(|_| form::Error::validation(format!("unknown value: {}", field.value)).into())
+            }
+        }
+    )*};
+}
+
+form_field_via_serde!(Klassifikation, Meldungsart, LieferengpassStatus, LetterSource, LetterType, LieferengpassSortField, SortOrder, CsvDelimiter, Dataset, StatsInterval);
+
+/// Date query parameter, accepts `2025-01-31` as well as `31.01.2025`.
+#[derive(Debug, Clone, Copy)]
+pub struct QueryDate(pub NaiveDate);
+
+impl<'v> FromFormField<'v> for QueryDate {
+    fn from_value(field: ValueField<'v>) -> form::Result<'v, Self> {
+        let value = field.value.trim();
+        NaiveDate::parse_from_str(value, "%Y-%m-%d")
+            .or_else(|_| NaiveDate::parse_from_str(value, "%d.%m.%Y"))
+            .map(QueryDate)
+            .map_err(|e| form::Error::validation(format!("invalid date {}: {}", value, e)).into())
+    }
+}
+
+/// Timestamp query parameter in RFC 3339, e.g. `2025-01-31T12:00:00Z`.
+#[derive(Debug, Clone, Copy)]
+pub struct QueryDateTime(pub DateTime<Utc>);
+
+impl<'v> FromFormField<'v> for QueryDateTime {
+    fn from_value(field: ValueField<'v>) -> form::Result<'v, Self> {
+        let value = field.value.trim();
+        DateTime::parse_from_rfc3339(value)
+            .map(|date_time| QueryDateTime(date_time.with_timezone(&Utc)))
+            .map_err(|e| form::Error::validation(format!("invalid timestamp {}: {}", value, e)).into())
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder{
+    #[default]
+    Asc,
+    Desc,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LieferengpassSortField{
+    Beginn,
+    Ende,
+    LetzteMeldung,
+    ErstmeldungDatum,
+}
+
+fn in_range(date: NaiveDate, from: Option<QueryDate>, to: Option<QueryDate>) -> bool{
+    from.is_none_or(|from| date >= from.0) && to.is_none_or(|to| date <= to.0)
+}
+
+fn contains_ignore_case(haystack: &str, needle: &str) -> bool{
+    haystack.to_lowercase().contains(&needle.trim().to_lowercase())
+}
+
+#[derive(FromForm, Debug, Default, Clone)]
+pub struct LieferengpassFilter{
+    pub pzn: Option<usize>,
+    pub enr: Option<usize>,
+    /// Prefix of the ATC code, e.g. `J01` for all systemic antibacterials.
+    pub atc: Option<String>,
+    pub wirkstoff: Option<String>,
+    pub zulassungsinhaber: Option<String>,
+    pub klassifikation: Option<Klassifikation>,
+    pub meldungsart: Option<Meldungsart>,
+    pub kkh_relevant: Option<bool>,
+    /// Without a status, vanished shortages are left out.
+    pub status: Option<LieferengpassStatus>,
+    pub beginn_from: Option<QueryDate>,
+    pub beginn_to: Option<QueryDate>,
+    pub ende_from: Option<QueryDate>,
+    pub ende_to: Option<QueryDate>,
+    pub letzte_meldung_from: Option<QueryDate>,
+    pub letzte_meldung_to: Option<QueryDate>,
+    pub sort: Option<LieferengpassSortField>,
+    pub order: Option<SortOrder>,
+}
+
+impl LieferengpassFilter{
+    pub fn matches(&self, entry: &Lieferengpass) -> bool{
+        match self.status{
+            Some(status) if entry.status != status => false,
+            None if entry.status == LieferengpassStatus::Vanished => false,
+            _ => self.matches_fields(entry),
+        }
+    }
+
+    /// Like [`Self::matches`], but ignores the status.
+    pub fn matches_fields(&self, entry: &Lieferengpass) -> bool{
+        if self.pzn.is_some_and(|pzn| entry.pzn != pzn){
+            return false;
+        }
+        if self.enr.is_some_and(|enr| !entry.enr.contains(&enr)){
+            return false;
+        }
+        if let Some(atc) = &self.atc && !entry.atc.to_uppercase().starts_with(&atc.trim().to_uppercase()){
+            return false;
+        }
+        if let Some(wirkstoff) = &self.wirkstoff && !contains_ignore_case(&entry.wirkstoffe, wirkstoff){
+            return false;
+        }
+        if let Some(zulassungsinhaber) = &self.zulassungsinhaber && !contains_ignore_case(&entry.zulassungsinhaber, zulassungsinhaber){
+            return false;
+        }
+        if self.klassifikation.as_ref().is_some_and(|klassifikation| &entry.klassifikation != klassifikation){
+            return false;
+        }
+        if self.meldungsart.as_ref().is_some_and(|meldungsart| &entry.meldungsart != meldungsart){
+            return false;
+        }
+        if self.kkh_relevant.is_some_and(|kkh_relevant| entry.kkh_relevant != kkh_relevant){
+            return false;
+        }
+        in_range(entry.beginn, self.beginn_from, self.beginn_to)
+            && in_range(entry.ende, self.ende_from, self.ende_to)
+            && in_range(entry.letzte_meldung, self.letzte_meldung_from, self.letzte_meldung_to)
+    }
+
+    /// Filters and sorts the given entries. Without `sort`, the upstream order is kept.
+    pub fn apply(&self, entries: &[Lieferengpass]) -> Vec<Lieferengpass>{
+        let mut results: Vec<Lieferengpass> = entries.iter().filter(|entry| self.matches(entry)).cloned().collect();
+
+        if let Some(sort) = self.sort{
+            let key = |entry: &Lieferengpass| match sort{
+                LieferengpassSortField::Beginn => entry.beginn,
+                LieferengpassSortField::Ende => entry.ende,
+                LieferengpassSortField::LetzteMeldung => entry.letzte_meldung,
+                LieferengpassSortField::ErstmeldungDatum => entry.erstmeldung_datum,
+            };
+            let descending = self.order.unwrap_or_default() == SortOrder::Desc;
+            results.sort_by(|a, b| {
+                let ordering = key(a).cmp(&key(b));
+                if descending { ordering.reverse() } else { ordering }
+            });
+        }
+
+        results
+    }
+}
+
+#[derive(FromForm, Debug, Default, Clone)]
+pub struct BriefFilter{
+    pub source: Option<LetterSource>,
+    pub letter_type: Option<LetterType>,
+    pub date_from: Option<QueryDate>,
+    pub date_to: Option<QueryDate>,
+    pub wirkstoff: Option<String>,
+    /// Free text, matched against title, descriptions and the text of the PDF.
+    pub q: Option<String>,
+    /// Sort order by date, newest first by default.
+    pub order: Option<SortOrder>,
+}
+
+impl BriefFilter{
+    pub fn matches(&self, brief: &Brief) -> bool{
+        if self.source.as_ref().is_some_and(|source| &brief.source != source){
+            return false;
+        }
+        if self.letter_type.as_ref().is_some_and(|letter_type| &brief.letter_type != letter_type){
+            return false;
+        }
+        if let Some(wirkstoff) = &self.wirkstoff{
+            let found = brief.wirkstoffe.as_ref().is_some_and(|wirkstoffe| wirkstoffe.iter().any(|entry| contains_ignore_case(entry, wirkstoff)));
+            if !found{
+                return false;
+            }
+        }
+        if let Some(q) = &self.q{
+            let found = contains_ignore_case(&brief.title, q)
+                || brief.short_description.as_ref().is_some_and(|description| contains_ignore_case(description, q))
+                || brief.long_description.as_ref().is_some_and(|description| contains_ignore_case(description, q))
+                || brief.full_text.as_ref().is_some_and(|full_text| contains_ignore_case(full_text, q));
+            if !found{
+                return false;
+            }
+        }
+
+        in_range(brief.date, self.date_from, self.date_to)
+    }
+
+    /// Filters the given letters and sorts them by date. Letters from the same day are ordered by link so the result is stable.
+    pub fn apply<'a>(&self, briefe: impl IntoIterator<Item = &'a Brief>) -> Vec<Brief>{
+        let mut results: Vec<Brief> = briefe.into_iter().filter(|brief| self.matches(brief)).cloned().collect();
+
+        let descending = self.order.unwrap_or(SortOrder::Desc) == SortOrder::Desc;
+        results.sort_by(|a, b| {
+            let ordering = a.date.cmp(&b.date).then_with(|| a.link_to_html.cmp(&b.link_to_html));
+            if descending { ordering.reverse() } else { ordering }
+        });
+
+        results
+    }
+}
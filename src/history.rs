@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::{Map, Value};
+use crate::changes::{ChangeEvent, ChangeKind, Dataset};
+use crate::lieferengpaesse::Lieferengpass;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FieldChange{
+    pub field: String,
+    pub old: Value,
+    pub new: Value,
+}
+
+/// Fields the API derives from other records and the current date. They are not part of what PharmNet reported, so
+/// versions neither show nor compare them.
+const DERIVED_FIELDS: [&str; 5] = ["wirkstoffe_normalized", "status", "vanished_at", "folgemeldungen", "related_briefe"];
+
+/// State of a Lieferengpass as first seen at `seen_at`, together with the changes to the previous version.
+#[derive(Serialize, Debug, Clone)]
+pub struct LieferengpassVersion{
+    pub seen_at: DateTime<Utc>,
+    pub changes: Vec<FieldChange>,
+    /// The record as parsed from the CSV, serialized without the fields the API derives.
+    #[serde(serialize_with = "serialize_reported")]
+    pub record: Lieferengpass,
+}
+
+/// The fields of `record` as reported by PharmNet, using the field names of the JSON output.
+fn reported_fields(record: &Lieferengpass) -> Map<String, Value>{
+    let Ok(Value::Object(mut fields)) = serde_json::to_value(record) else {
+        return Map::new();
+    };
+    for field in DERIVED_FIELDS{
+        fields.remove(field);
+    }
+    fields
+}
+
+fn serialize_reported<S: Serializer>(record: &Lieferengpass, serializer: S) -> Result<S::Ok, S::Error>{
+    reported_fields(record).serialize(serializer)
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct LieferengpassHistory{
+    pub bearbeitungsnummer: String,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    /// Oldest version first.
+    pub versions: Vec<LieferengpassVersion>,
+    /// How many of the versions are already stored by the persistence backend.
+    #[serde(skip)]
+    pub persisted_versions: usize,
+}
+
+impl LieferengpassHistory{
+    pub fn new(record: Lieferengpass, seen_at: DateTime<Utc>) -> Self{
+        LieferengpassHistory{
+            bearbeitungsnummer: record.bearbeitungsnummer.clone(),
+            first_seen: seen_at,
+            last_seen: seen_at,
+            versions: vec![LieferengpassVersion{
+                seen_at,
+                changes: Vec::new(),
+                record,
+            }],
+            persisted_versions: 0,
+        }
+    }
+
+    /// Copy holding only the versions that are not stored yet.
+    pub fn unpersisted(&self) -> LieferengpassHistory{
+        LieferengpassHistory{
+            bearbeitungsnummer: self.bearbeitungsnummer.clone(),
+            first_seen: self.first_seen,
+            last_seen: self.last_seen,
+            versions: self.versions.get(self.persisted_versions..).unwrap_or_default().to_vec(),
+            persisted_versions: 0,
+        }
+    }
+
+    pub fn current(&self) -> Option<&Lieferengpass>{
+        self.versions.last().map(|version| &version.record)
+    }
+
+    /// Records that `record` was seen at `seen_at`. Adds a new version if any field changed and returns the changes.
+    pub fn observe(&mut self, record: &Lieferengpass, seen_at: DateTime<Utc>) -> Vec<FieldChange>{
+        self.last_seen = seen_at;
+
+        let changes = match self.current(){
+            Some(current) => diff(current, record),
+            None => Vec::new(),
+        };
+        if !changes.is_empty(){
+            self.versions.push(LieferengpassVersion{
+                seen_at,
+                changes: changes.clone(),
+                record: record.clone(),
+            });
+        }
+        changes
+    }
+}
+
+/// Compares the reported fields of two records, using the field names of the JSON output.
+pub fn diff(old: &Lieferengpass, new: &Lieferengpass) -> Vec<FieldChange>{
+    let old = reported_fields(old);
+    reported_fields(new).into_iter()
+        .filter_map(|(field, new_value)| {
+            let old_value = old.get(&field).cloned().unwrap_or(Value::Null);
+            (old_value != new_value).then_some(FieldChange{
+                field,
+                old: old_value,
+                new: new_value,
+            })
+        })
+        .collect()
+}
+
+/// Updates the histories with the records of a refresh and returns the added and modified records.
+pub fn record(histories: &mut HashMap<String, LieferengpassHistory>, records: &[Lieferengpass], seen_at: DateTime<Utc>) -> Vec<ChangeEvent>{
+    let mut events = Vec::new();
+    for record in records{
+        let kind = match histories.get_mut(&record.bearbeitungsnummer){
+            Some(history) => {
+                if history.observe(record, seen_at).is_empty(){
+                    continue;
+                }
+                ChangeKind::Modified
+            }
+            None => {
+                histories.insert(record.bearbeitungsnummer.clone(), LieferengpassHistory::new(record.clone(), seen_at));
+                ChangeKind::Added
+            }
+        };
+        events.push(ChangeEvent{
+            at: seen_at,
+            dataset: Dataset::Lieferengpaesse,
+            id: record.bearbeitungsnummer.clone(),
+            kind,
+        });
+    }
+    events
+}
@@ -0,0 +1,218 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration};
+use chrono::{DateTime, Utc};
+use rocket::{routes, tokio, Build, Rocket};
+use rocket::tokio::sync::RwLock;
+use rocket::tokio::time::Instant;
+use tracing::Instrument;
+use crate::history::LieferengpassHistory;
+use crate::atc::AtcNames;
+use crate::lieferengpaesse::{Lieferengpass, RejectedRow};
+use crate::caching::DataVersion;
+use crate::changes::ChangeEvent;
+use crate::compression::Compression;
+use crate::metrics::RequestMetrics;
+use crate::config::AppConfig;
+use crate::persistence::Persistence;
+use crate::search::SearchIndex;
+use crate::status::{Source, SourceStatus};
+use crate::wirkstoffe::WirkstoffDictionary;
+use crate::rote_hand_briefe::{crawl_bfarm, crawl_pei, detect_pei_wirkstoffe, extract_full_texts, Brief};
+use crate::webhooks::Webhook;
+
+pub mod lieferengpaesse;
+pub mod rote_hand_briefe;
+pub mod persistence;
+pub mod history;
+pub mod changes;
+pub mod webhooks;
+pub mod feeds;
+pub mod export;
+pub mod api;
+pub mod config;
+pub mod filter;
+pub mod pagination;
+pub mod search;
+pub mod links;
+pub mod wirkstoffe;
+pub mod atc;
+pub mod stats;
+pub mod caching;
+pub mod compression;
+pub mod status;
+pub mod metrics;
+pub mod logging;
+
+pub struct TempStorage{
+    pub storage: RwLock<InnerStorage>,
+    pub persistence: Arc<dyn Persistence>,
+    pub config: AppConfig,
+    /// Wirkstoffe searched for in PEI letters.
+    pub wirkstoff_dictionary: WirkstoffDictionary,
+    /// Names of the ATC groups.
+    pub atc_names: AtcNames,
+}
+
+impl TempStorage{
+    /// Creates the storage and fills it with the data previously saved to `persistence`.
+    pub fn load(config: AppConfig, persistence: Arc<dyn Persistence>) -> Self{
+        let mut inner = InnerStorage::default();
+
+        match persistence.load(){
+            Ok(snapshot) => {
+                tracing::info!(lieferengpaesse = snapshot.lieferengpaesse.len(), briefe = snapshot.briefe.len(), "Loaded stored data");
+                inner.lieferengpaesse_loaded_initially = snapshot.lieferengpaesse_refreshed_at.is_some();
+                inner.lieferengpaesse = snapshot.lieferengpaesse;
+                lieferengpaesse::update_lifecycle(&mut inner.lieferengpaesse, Utc::now().date_naive());
+                inner.lieferengpaesse_refreshed_at = snapshot.lieferengpaesse_refreshed_at;
+                inner.lieferengpaesse_history = snapshot.lieferengpaesse_history;
+                inner.briefe_loaded_initially = snapshot.briefe_refreshed_at.is_some();
+                inner.briefe = snapshot.briefe;
+                inner.briefe_refreshed_at = snapshot.briefe_refreshed_at;
+                inner.changes = snapshot.changes;
+                inner.changes_log_start = snapshot.changes_log_start;
+                inner.webhooks = snapshot.webhooks;
+            }
+            Err(e) => tracing::error!(error = %e, "Failed to load stored data"),
+        }
+        inner.update_derived_data();
+
+        TempStorage{
+            storage: RwLock::new(inner),
+            persistence,
+            wirkstoff_dictionary: WirkstoffDictionary::load(config.pei_wirkstoffe.as_deref()),
+            atc_names: AtcNames::load(config.atc_names.as_deref()),
+            config,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct InnerStorage{
+    pub briefe_loaded_initially: bool,
+    pub lieferengpaesse_loaded_initially: bool,
+    pub briefe_refreshed_at: Option<DateTime<Utc>>,
+    pub lieferengpaesse_refreshed_at: Option<DateTime<Utc>>,
+    /// Content versions for conditional requests, updated with the derived data.
+    pub briefe_version: Option<DataVersion>,
+    pub lieferengpaesse_version: Option<DataVersion>,
+    /// Changes noticed during the refreshes, oldest first.
+    pub changes: Vec<ChangeEvent>,
+    /// Changes after this point in time are complete in `changes`.
+    pub changes_log_start: Option<DateTime<Utc>>,
+    pub lieferengpaesse: Vec<Lieferengpass>,
+    /// Rows of the latest PharmNet CSV that could not be parsed, `None` until it was fetched. Not persisted.
+    pub lieferengpaesse_rejected: Option<Vec<RejectedRow>>,
+    /// History of every Lieferengpass ever seen, by Bearbeitungsnummer.
+    pub lieferengpaesse_history: HashMap<String, LieferengpassHistory>,
+    pub briefe: HashMap<String, Brief>,
+    /// Letters whose PDF could not be read, by `link_to_html`. Retried after a restart.
+    pub full_text_failed: HashSet<String>,
+    /// Where the next refresh continues checking known PEI letters again, see `pei_recheck_letters`. Not persisted.
+    pub pei_recheck_offset: usize,
+    pub webhooks: Vec<Webhook>,
+    /// Outcome of the latest crawls, not persisted.
+    pub source_status: BTreeMap<Source, SourceStatus>,
+    /// Full-text index over `briefe` and `lieferengpaesse`, updated after each refresh.
+    pub search_index: SearchIndex,
+    pub reqwest_client: reqwest::Client,
+}
+
+impl InnerStorage{
+    /// Recomputes the normalized Wirkstoffe, the cross-links, the search index and the data versions. Called under the
+    /// same write lock that changes `lieferengpaesse` or `briefe`, so readers never see records without them.
+    pub fn update_derived_data(&mut self){
+        wirkstoffe::update(self);
+        links::update(self);
+        search::sync(self);
+        caching::update(self);
+    }
+}
+
+/// Mounts all routes and attaches the fairings. Rocket only reports colliding routes on launch, the tests build the same set.
+pub fn mount(rocket: Rocket<Build>, storage: Arc<TempStorage>) -> Rocket<Build>{
+    rocket
+        .mount("/api", routes![api::status, api::changes, api::search, api::atc_groups, api::atc_group, api::stats_summary, api::stats_timeline, api::lieferengpaesse, api::lieferengpaesse_rejected, api::lieferengpass, api::lieferengpass_by_pzn, api::lieferengpass_history, api::briefe, api::brief, api::create_webhook, api::webhook, api::delete_webhook, export::lieferengpaesse_csv, export::lieferengpaesse_xlsx])
+        .mount("/feeds", routes![feeds::briefe_atom, feeds::briefe_rss])
+        .mount("/", routes![metrics::metrics])
+        .attach(Compression::default())
+        // After compression, so the latency includes it
+        .attach(RequestMetrics)
+        .manage(storage)
+}
+
+pub async fn refresh_worker(storage: Arc<TempStorage>){
+    tokio::task::spawn(async move {
+        let mut webhooks_dispatched_until = Utc::now();
+        let mut cycle: u64 = 0;
+
+        loop{
+            let last_refresh = Instant::now();
+            cycle += 1;
+            let initial_load = {
+                let handle = storage.storage.read().await;
+                !handle.briefe_loaded_initially || !handle.lieferengpaesse_loaded_initially
+            };
+
+            let refreshed = async {
+                tracing::info!("Starting refresh");
+
+                let (attempted_at, started) = (Utc::now(), Instant::now());
+                let result = crawl_pei(storage.clone()).await;
+                status::record(&storage, Source::Pei, attempted_at, started.elapsed(), &result).await;
+                if let Err(e) = result{
+                    tracing::error!(source = "pei", error = %e, "Failed to crawl letters");
+                }
+
+                let (attempted_at, started) = (Utc::now(), Instant::now());
+                let result = crawl_bfarm(storage.clone()).await;
+                status::record(&storage, Source::Bfarm, attempted_at, started.elapsed(), &result).await;
+                if let Err(e) = result{
+                    tracing::error!(source = "bfarm", error = %e, "Failed to crawl letters");
+                }
+
+                if !storage.storage.read().await.briefe_loaded_initially{
+                    storage.storage.write().await.briefe_loaded_initially = true;
+                }
+                persistence::save_briefe(storage.clone()).await;
+
+                let extracted = extract_full_texts(storage.clone()).await;
+                if extracted + detect_pei_wirkstoffe(storage.clone()).await > 0{
+                    persistence::save_briefe(storage.clone()).await;
+                }
+
+                let (attempted_at, started) = (Utc::now(), Instant::now());
+                let result = lieferengpaesse::refresh_lieferengpaesse(storage.clone()).await;
+                status::record(&storage, Source::Pharmnet, attempted_at, started.elapsed(), &result).await;
+                if let Err(e) = result{
+                    tracing::error!(source = "pharmnet", error = %e, "Failed to refresh Lieferengpässe, trying again in 5 seconds");
+                    return false;
+                };
+
+                if !storage.storage.read().await.lieferengpaesse_loaded_initially{
+                    storage.storage.write().await.lieferengpaesse_loaded_initially = true;
+                }
+                persistence::save_lieferengpaesse(storage.clone()).await;
+                persistence::save_changes(storage.clone()).await;
+
+                // Don't notify about everything that was added by the initial load
+                webhooks_dispatched_until = if initial_load{
+                    Utc::now()
+                }else{
+                    webhooks::dispatch(storage.clone(), webhooks_dispatched_until).await
+                };
+
+                let handle = storage.storage.read().await;
+                tracing::info!(lieferengpaesse = handle.lieferengpaesse.len(), briefe = handle.briefe.len(), "Refresh finished, waiting for the next refresh interval");
+                true
+            }.instrument(tracing::info_span!("refresh", cycle)).await;
+
+            if !refreshed{
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                continue;
+            }
+            tokio::time::sleep_until(last_refresh + Duration::from_mins(15)).await;
+        }
+    });
+}
@@ -1,36 +1,151 @@
+use std::collections::{HashMap, HashSet};
 use std::num::ParseIntError;
 use std::sync::Arc;
-use chrono::NaiveDate;
+use chrono::{DateTime, Duration, NaiveDate, Utc};
 use rocket::serde::Deserialize;
 use serde::Serialize;
-use crate::TempStorage;
+use crate::changes::{ChangeEvent, ChangeKind, Dataset};
+use crate::config::ParseMode;
+use crate::status::{CrawlReport, Source};
+use crate::{changes, history, metrics, TempStorage};
 
-pub async fn refresh_lieferengpaesse(storage: Arc<TempStorage>) -> Result<(), reqwest::Error>{
+#[tracing::instrument(name = "crawl", skip_all, fields(source = "pharmnet"))]
+pub async fn refresh_lieferengpaesse(storage: Arc<TempStorage>) -> Result<CrawlReport, reqwest::Error>{
     let client = storage.storage.read().await.reqwest_client.clone();
     // Get csv
-    let request = client.get("https://anwendungen.pharmnet-bund.de/lieferengpassmeldungen/public/csv").build()?;
-    let response = client.execute(request).await?.text_with_charset("WINDOWS-1252").await?;
+    let base_url = storage.config.pharmnet_base_url.trim_end_matches('/');
+    let url = format!("{}/lieferengpassmeldungen/public/csv", base_url);
+    let request = client.get(&url).build()?;
+    let response = metrics::observe_upstream(Source::Pharmnet, client.execute(request).await)?.text_with_charset("WINDOWS-1252").await?;
+    tracing::debug!(%url, bytes = response.len(), "Downloaded CSV");
 
+    // The csv crate counts lines wrong with CRLF line endings
+    let response = response.replace("\r\n", "\n");
 
-    // Parse csv
-    let mut rdr = csv::ReaderBuilder::new().flexible(false).delimiter(b';').from_reader(response.as_bytes());
+    // Parse csv. Rows are read as strings first, so rows that can't be parsed can be kept as they are.
+    // Rows with the wrong number of fields are rejected by `parse_row`.
+    let mut rdr = csv::ReaderBuilder::new().flexible(true).delimiter(b';').from_reader(response.as_bytes());
+    let headers = rdr.headers().cloned().unwrap_or_default();
+    let id_column = headers.iter().position(|header| header == "Bearbeitungsnummer");
+    let mode = storage.config.lieferengpaesse_parse_mode;
 
     let mut results = Vec::new();
-    for result in rdr.deserialize() {
-        // We must tell Serde what type we want to deserialize into.
-        let record: Lieferengpass = match result{
-            Ok(record) => record,
+    let mut rejected = Vec::new();
+    let mut report = CrawlReport::default();
+    for row in rdr.records() {
+        let row = match row{
+            Ok(row) => row,
             Err(error) => {
-                eprintln!("Cant parse record: ");
-                eprintln!("{}", error);
+                tracing::warn!(line = error.position().map(|position| position.line()), %error, "Can't read CSV row");
+                report.skip(skip_reason(&error));
                 continue;
             }
         };
-        results.push(record);
+        let line = row.position().map_or(0, |position| position.line());
+        let bearbeitungsnummer = id_column.and_then(|column| row.get(column)).map(str::to_string);
+        match parse_row(&row, &headers, mode){
+            Ok(record) => {
+                for (column, value) in record.unknown_values(){
+                    tracing::warn!(line, record_id = bearbeitungsnummer.as_deref(), column, value, "Keeping unknown value");
+                }
+                results.push(record);
+            }
+            Err(error) => {
+                tracing::warn!(line, record_id = bearbeitungsnummer.as_deref(), %error, "Can't parse record");
+                report.skip(error.clone());
+                rejected.push(RejectedRow{ line, bearbeitungsnummer, raw: raw_row(&row), error });
+            }
+        }
+    }
+    report.parsed = results.len();
+    let now = Utc::now();
+    let mut handle = storage.storage.write().await;
+    let mut events = history::record(&mut handle.lieferengpaesse_history, &results, now);
+
+    // Keep shortages that are no longer listed, marked as vanished, until the retention has passed
+    let listed: HashSet<String> = results.iter().map(|entry| entry.bearbeitungsnummer.clone()).collect();
+    let retention = Duration::days(storage.config.vanished_retention_days);
+    for previous in &handle.lieferengpaesse{
+        if !listed.contains(&previous.bearbeitungsnummer){
+            if previous.vanished_at.is_some_and(|vanished_at| vanished_at < now - retention){
+                tracing::info!(record_id = %previous.bearbeitungsnummer, "Dropping vanished shortage");
+                continue;
+            }
+            let mut vanished = previous.clone();
+            if vanished.vanished_at.is_none(){
+                vanished.vanished_at = Some(now);
+                events.push(ChangeEvent{
+                    at: now,
+                    dataset: Dataset::Lieferengpaesse,
+                    id: vanished.bearbeitungsnummer.clone(),
+                    kind: ChangeKind::Removed,
+                });
+            }
+            results.push(vanished);
+        }
+    }
+    update_lifecycle(&mut results, now.date_naive());
+    changes::log(&mut handle, events);
+
+    handle.lieferengpaesse = results;
+    handle.lieferengpaesse_rejected = Some(rejected);
+    handle.lieferengpaesse_refreshed_at = Some(now);
+    handle.update_derived_data();
+    tracing::info!(parsed = report.parsed, skipped = report.skipped.values().sum::<usize>(), "Refreshed Lieferengpässe");
+    Ok(report)
+}
+
+/// The error without its position, so rows failing for the same reason are counted together.
+fn skip_reason(error: &csv::Error) -> String{
+    match error.kind(){
+        csv::ErrorKind::Deserialize{ err, .. } => err.to_string(),
+        _ => error.to_string(),
+    }
+}
+
+/// Parses a CSV row. Values none of the enum variants match are rejected unless `mode` is lenient.
+fn parse_row(row: &csv::StringRecord, headers: &csv::StringRecord, mode: ParseMode) -> Result<Lieferengpass, String>{
+    if row.len() != headers.len(){
+        return Err("wrong number of fields".to_string());
+    }
+    let record: Lieferengpass = row.deserialize(Some(headers)).map_err(|error| skip_reason(&error))?;
+    if mode == ParseMode::Strict && let Some((column, value)) = record.unknown_values().first(){
+        return Err(format!("unknown variant `{}` in {}", value, column));
+    }
+    Ok(record)
+}
+
+/// The row in CSV format, as PharmNet sends it unless it quoted fields that don't need it.
+fn raw_row(row: &csv::StringRecord) -> String{
+    let mut writer = csv::WriterBuilder::new().delimiter(b';').terminator(csv::Terminator::Any(b'\n')).from_writer(Vec::new());
+    if writer.write_record(row).is_err(){
+        return row.iter().collect::<Vec<_>>().join(";");
+    }
+    let raw = writer.into_inner().unwrap_or_default();
+    String::from_utf8_lossy(&raw).trim_end_matches('\n').to_string()
+}
+
+/// Sets `status` and `folgemeldungen` of all entries. Both are derived data and not part of the CSV.
+pub fn update_lifecycle(entries: &mut [Lieferengpass], today: NaiveDate){
+    let mut folgemeldungen: HashMap<String, Vec<String>> = HashMap::new();
+    for entry in entries.iter(){
+        if let Some(erstmeldung) = &entry.erstmeldung && erstmeldung != &entry.bearbeitungsnummer{
+            folgemeldungen.entry(erstmeldung.clone()).or_default().push(entry.bearbeitungsnummer.clone());
+        }
+    }
+
+    for entry in entries.iter_mut(){
+        entry.status = if entry.meldungsart == Meldungsart::Loeschmeldung{
+            LieferengpassStatus::Deleted
+        }else if entry.vanished_at.is_some(){
+            LieferengpassStatus::Vanished
+        }else if entry.ende < today{
+            LieferengpassStatus::Ended
+        }else{
+            LieferengpassStatus::Active
+        };
+        entry.folgemeldungen = folgemeldungen.remove(&entry.bearbeitungsnummer).unwrap_or_default();
     }
-    storage.storage.write().await.lieferengpaesse = results;
-    println!("Refreshed Lieferengpässe.");
-    Ok(())
 }
 
 pub fn deserialize_na_option<'de, D>(deserializer: D) -> Result<Option<String>, D::Error> where D: serde::Deserializer<'de>{
@@ -104,6 +219,9 @@ pub struct Lieferengpass{
     pub atc: String,
     #[serde(rename(deserialize = "Wirkstoffe"))]
     pub wirkstoffe: String,
+    /// `wirkstoffe` split into single Wirkstoffe, without strengths and salt forms, see [`crate::wirkstoffe`].
+    #[serde(skip_deserializing)]
+    pub wirkstoffe_normalized: Vec<String>,
     #[serde(rename(deserialize = "Krankenhausrelevant"), deserialize_with = "bool_ja_nein")]
     pub kkh_relevant: bool,
     #[serde(rename(deserialize = "Zulassungsinhaber"))]
@@ -120,16 +238,74 @@ pub struct Lieferengpass{
     pub darreichungsform: String,
     #[serde(rename(deserialize = "klassifikation"))]
     pub klassifikation: Klassifikation,
+    #[serde(skip_deserializing)]
+    pub status: LieferengpassStatus,
+    /// When the shortage was first missing from the PharmNet CSV.
+    #[serde(skip_deserializing)]
+    pub vanished_at: Option<DateTime<Utc>>,
+    /// Bearbeitungsnummern of the reports referencing this one as their Erstmeldung.
+    #[serde(skip_deserializing)]
+    pub folgemeldungen: Vec<String>,
+    /// Ids of letters on the same Wirkstoff or ATC code.
+    #[serde(skip_deserializing)]
+    pub related_briefe: Vec<String>,
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
+impl Lieferengpass{
+    /// CSV columns whose value is kept as `Other`, with the value. Values PharmNet added to the enums that are not known
+    /// yet end up in the `Other` variants; such rows are rejected unless the [`ParseMode`] is lenient.
+    pub fn unknown_values(&self) -> Vec<(&'static str, &str)>{
+        let mut unknown = Vec::new();
+        if let Meldungsart::Other(value) = &self.meldungsart{
+            unknown.push(("Meldungsart", value.as_str()));
+        }
+        if let ArtDesGrundes::Other(value) = &self.art_des_grundes{
+            unknown.push(("Art des Grundes", value.as_str()));
+        }
+        if let InfoAnFachkreise::Other(value) = &self.info_an_fachkreise{
+            unknown.push(("Info an Fachkreise", value.as_str()));
+        }
+        if let Klassifikation::Other(value) = &self.klassifikation{
+            unknown.push(("klassifikation", value.as_str()));
+        }
+        unknown
+    }
+}
+
+/// A row of the PharmNet CSV that could not be parsed, kept until the next refresh.
+#[derive(Serialize, Debug, Clone)]
+pub struct RejectedRow{
+    /// Line in the CSV, the header is line 1.
+    pub line: u64,
+    pub bearbeitungsnummer: Option<String>,
+    /// The row as it was in the CSV.
+    pub raw: String,
+    pub error: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LieferengpassStatus{
+    #[default]
+    Active,
+    /// `ende` is in the past.
+    Ended,
+    /// Withdrawn by a Löschmeldung.
+    Deleted,
+    /// No longer listed in the PharmNet CSV.
+    Vanished,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
 pub enum Klassifikation{
     #[serde(rename = "weder versrel noch verskri")]
     WederVersorgungsrelevantNochVersorgungskritisch,
     #[serde(rename = "versrel")]
     Versorgungsrelevant,
     #[serde(rename = "verskri (auch versrel)")]
-    VersorgungsrelevantAuchVersorgungskritisch
+    VersorgungsrelevantAuchVersorgungskritisch,
+    #[serde(untagged)]
+    Other(String),
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -138,20 +314,26 @@ pub enum InfoAnFachkreise{
     Ja,
     Vorgesehen,
     #[serde(rename = "N/A")]
-    Unbekannt
+    Unbekannt,
+    #[serde(untagged)]
+    Other(String),
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub enum ArtDesGrundes{
     Produktionsproblem,
-    Sonstige
+    Sonstige,
+    #[serde(untagged)]
+    Other(String),
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub enum Meldungsart{
     Erstmeldung,
     #[serde(rename = "Änderungsmeldung")]
     Aenderungsmeldung,
     #[serde(rename = "Löschmeldung")]
-    Loeschmeldung
+    Loeschmeldung,
+    #[serde(untagged)]
+    Other(String),
 }
\ No newline at end of file
@@ -0,0 +1,54 @@
+use std::collections::{BTreeSet, HashMap};
+use crate::lieferengpaesse::LieferengpassStatus;
+use crate::InnerStorage;
+
+/// Links letters and Lieferengpässe concerning the same Wirkstoff, compared by their normalized Wirkstoffe.
+///
+/// A letter is linked to every shortage listing one of its Wirkstoffe, and to every shortage with the ATC
+/// code of such a directly matched shortage. Letters only list active shortages, shortages list all letters.
+pub fn update(storage: &mut InnerStorage){
+    let mut by_name: HashMap<&str, Vec<usize>> = HashMap::new();
+    let mut by_atc: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (index, entry) in storage.lieferengpaesse.iter().enumerate(){
+        for name in &entry.wirkstoffe_normalized{
+            by_name.entry(name.as_str()).or_default().push(index);
+        }
+        let atc = entry.atc.trim();
+        if !atc.is_empty(){
+            by_atc.entry(atc).or_default().push(index);
+        }
+    }
+
+    let mut related_briefe: Vec<BTreeSet<String>> = vec![BTreeSet::new(); storage.lieferengpaesse.len()];
+    let mut related_lieferengpaesse: HashMap<String, Vec<String>> = HashMap::new();
+    for (key, brief) in &storage.briefe{
+        let mut direct: BTreeSet<usize> = BTreeSet::new();
+        for name in &brief.wirkstoffe_normalized{
+            direct.extend(by_name.get(name.as_str()).into_iter().flatten());
+        }
+
+        let mut linked = direct.clone();
+        for index in &direct{
+            linked.extend(by_atc.get(storage.lieferengpaesse[*index].atc.trim()).into_iter().flatten());
+        }
+
+        let mut active = Vec::new();
+        for index in linked{
+            let entry = &storage.lieferengpaesse[index];
+            related_briefe[index].insert(brief.id.clone());
+            if entry.status == LieferengpassStatus::Active{
+                active.push(entry.bearbeitungsnummer.clone());
+            }
+        }
+        active.sort();
+        active.dedup();
+        related_lieferengpaesse.insert(key.clone(), active);
+    }
+
+    for (entry, related) in storage.lieferengpaesse.iter_mut().zip(related_briefe){
+        entry.related_briefe = related.into_iter().collect();
+    }
+    for (key, brief) in storage.briefe.iter_mut(){
+        brief.related_lieferengpaesse = related_lieferengpaesse.remove(key).unwrap_or_default();
+    }
+}
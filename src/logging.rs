@@ -0,0 +1,16 @@
+use tracing_subscriber::EnvFilter;
+use crate::config::LogFormat;
+
+/// Installs the global subscriber. Levels are taken from `RUST_LOG`, `info` by default.
+pub fn init(format: LogFormat){
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    // Not `init()`, that would also route the `log` records of Rocket, which prints them itself
+    let result = match format{
+        LogFormat::Text => tracing::subscriber::set_global_default(builder.finish()),
+        LogFormat::Json => tracing::subscriber::set_global_default(builder.json().with_current_span(true).with_span_list(true).finish()),
+    };
+    if let Err(e) = result{
+        eprintln!("Failed to set up logging: {}", e);
+    }
+}
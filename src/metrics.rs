@@ -0,0 +1,117 @@
+use std::error::Error;
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
+use prometheus::{register_histogram_vec, register_int_counter_vec, register_int_gauge_vec, Encoder, HistogramVec, IntCounterVec, IntGaugeVec, TextEncoder};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{ContentType, Status};
+use rocket::{get, Data, Request, Response, State};
+use crate::status::{CrawlReport, Source};
+use crate::TempStorage;
+
+/// Refreshes take from a second up to several minutes with the letter details.
+const REFRESH_BUCKETS: &[f64] = &[1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0];
+
+static REFRESH_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| register_histogram_vec!(
+    "medihelp_refresh_duration_seconds", "Duration of the refreshes per source.", &["source", "outcome"], REFRESH_BUCKETS.to_vec()
+).unwrap());
+static RECORDS_PARSED: LazyLock<IntGaugeVec> = LazyLock::new(|| register_int_gauge_vec!(
+    "medihelp_records_parsed", "Records parsed by the last successful refresh.", &["source"]
+).unwrap());
+static RECORDS_SKIPPED: LazyLock<IntGaugeVec> = LazyLock::new(|| register_int_gauge_vec!(
+    "medihelp_records_skipped", "Records skipped by the last successful refresh.", &["source"]
+).unwrap());
+static UPSTREAM_RESPONSES: LazyLock<IntCounterVec> = LazyLock::new(|| register_int_counter_vec!(
+    "medihelp_upstream_responses_total", "Responses of the upstream sites by status code, `error` if none was received.", &["source", "status"]
+).unwrap());
+static HTTP_REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| register_int_counter_vec!(
+    "medihelp_http_requests_total", "Requests handled per route.", &["method", "route", "status"]
+).unwrap());
+static HTTP_REQUEST_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| register_histogram_vec!(
+    "medihelp_http_request_duration_seconds", "Time to build the response per route, without sending the body.", &["method", "route"]
+).unwrap());
+static DATASET_SIZE: LazyLock<IntGaugeVec> = LazyLock::new(|| register_int_gauge_vec!(
+    "medihelp_dataset_size", "Entries currently held in memory.", &["dataset"]
+).unwrap());
+
+fn source_label(source: Source) -> &'static str{
+    match source{
+        Source::Bfarm => "bfarm",
+        Source::Pei => "pei",
+        Source::Pharmnet => "pharmnet",
+    }
+}
+
+/// Records the duration and counts of a refresh.
+pub fn observe_refresh<E: Error>(source: Source, duration: Duration, result: &Result<CrawlReport, E>){
+    let source = source_label(source);
+    let outcome = if result.is_ok() { "success" } else { "error" };
+    REFRESH_DURATION.with_label_values(&[source, outcome]).observe(duration.as_secs_f64());
+    if let Ok(report) = result{
+        RECORDS_PARSED.with_label_values(&[source]).set(report.parsed as i64);
+        RECORDS_SKIPPED.with_label_values(&[source]).set(report.skipped.values().sum::<usize>() as i64);
+    }
+}
+
+/// Counts the status code of an upstream response, passing the result through.
+pub fn observe_upstream(source: Source, result: reqwest::Result<reqwest::Response>) -> reqwest::Result<reqwest::Response>{
+    let status = match &result{
+        Ok(response) => response.status().as_u16().to_string(),
+        Err(_) => "error".to_string(),
+    };
+    UPSTREAM_RESPONSES.with_label_values(&[source_label(source), &status]).inc();
+    result
+}
+
+/// When the request arrived, kept in the request's local cache.
+struct RequestStart(Option<Instant>);
+
+/// Counts requests and measures their latency per route. Requests that match no route are counted as `unmatched`.
+pub struct RequestMetrics;
+
+#[rocket::async_trait]
+impl Fairing for RequestMetrics {
+    fn info(&self) -> Info {
+        Info{ name: "Request metrics", kind: Kind::Request | Kind::Response }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        request.local_cache(|| RequestStart(Some(Instant::now())));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        // The template, not the requested path, to keep the number of series bounded
+        let route = request.route().map_or_else(|| "unmatched".to_string(), |route| route.uri.origin.path().to_string());
+        let method = request.method().as_str();
+        HTTP_REQUESTS.with_label_values(&[method, &route, &response.status().code.to_string()]).inc();
+        if let Some(started) = request.local_cache(|| RequestStart(None)).0{
+            HTTP_REQUEST_DURATION.with_label_values(&[method, &route]).observe(started.elapsed().as_secs_f64());
+        }
+    }
+}
+
+/// All metrics in the Prometheus text format.
+#[get("/metrics")]
+pub async fn metrics(storage: &State<Arc<TempStorage>>) -> Result<(ContentType, String), Status> {
+    {
+        let handle = storage.storage.read().await;
+        for (dataset, size) in [
+            ("lieferengpaesse", handle.lieferengpaesse.len()),
+            ("lieferengpaesse_history", handle.lieferengpaesse_history.len()),
+            ("lieferengpaesse_rejected", handle.lieferengpaesse_rejected.as_ref().map_or(0, Vec::len)),
+            ("briefe", handle.briefe.len()),
+            ("changes", handle.changes.len()),
+            ("webhooks", handle.webhooks.len()),
+        ]{
+            DATASET_SIZE.with_label_values(&[dataset]).set(size as i64);
+        }
+    }
+
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buffer){
+        tracing::error!(error = %e, "Failed to encode metrics");
+        return Err(Status::InternalServerError);
+    }
+    let content_type = ContentType::parse_flexible(encoder.format_type()).unwrap_or(ContentType::Plain);
+    String::from_utf8(buffer).map(|body| (content_type, body)).map_err(|_| Status::InternalServerError)
+}
@@ -0,0 +1,98 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+/// Response envelope for list endpoints.
+#[derive(Serialize, Clone)]
+pub struct Page<T>{
+    /// Number of entries matching the filter, independent of `limit` and `offset`.
+    pub total: usize,
+    pub offset: usize,
+    pub limit: Option<usize>,
+    /// Pass as `cursor` to get the next page. `None` on the last page.
+    pub next_cursor: Option<String>,
+    /// Time of the last successful refresh of the underlying data.
+    pub data_timestamp: Option<DateTime<Utc>>,
+    pub items: Vec<T>,
+}
+
+impl<T> Page<T>{
+    /// Converts the items of the page, e.g. to look up the records of the returned entries only.
+    pub fn map<U>(self, f: impl FnOnce(Vec<T>) -> Vec<U>) -> Page<U>{
+        Page{
+            total: self.total,
+            offset: self.offset,
+            limit: self.limit,
+            next_cursor: self.next_cursor,
+            data_timestamp: self.data_timestamp,
+            items: f(self.items),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Pagination{
+    pub limit: Option<usize>,
+    pub offset: usize,
+}
+
+impl Pagination{
+    /// Builds the pagination from the query parameters. A cursor takes precedence over `offset` and keeps the limit of the page it was issued for unless `limit` is given.
+    pub fn from_query(limit: Option<usize>, offset: Option<usize>, cursor: Option<&str>) -> Result<Self, &'static str>{
+        let pagination = match cursor{
+            Some(cursor) => {
+                let cursor = decode_cursor(cursor).ok_or("invalid cursor")?;
+                Pagination{
+                    limit: limit.or(cursor.limit),
+                    offset: cursor.offset,
+                }
+            }
+            None => Pagination{
+                limit,
+                offset: offset.unwrap_or(0),
+            },
+        };
+        // An empty page would never advance the cursor
+        if pagination.limit == Some(0){
+            return Err("limit must be at least 1");
+        }
+        Ok(pagination)
+    }
+
+    pub fn paginate<T>(&self, items: Vec<T>, data_timestamp: Option<DateTime<Utc>>) -> Page<T>{
+        let total = items.len();
+        let items: Vec<T> = items.into_iter().skip(self.offset).take(self.limit.unwrap_or(usize::MAX)).collect();
+
+        let next_offset = self.offset + items.len();
+        let next_cursor = if self.limit.is_some() && !items.is_empty() && next_offset < total{
+            Some(encode_cursor(&Pagination{ limit: self.limit, offset: next_offset }))
+        }else{
+            None
+        };
+
+        Page{
+            total,
+            offset: self.offset,
+            limit: self.limit,
+            next_cursor,
+            data_timestamp,
+            items,
+        }
+    }
+}
+
+fn encode_cursor(pagination: &Pagination) -> String{
+    let limit = pagination.limit.map(|limit| limit.to_string()).unwrap_or_default();
+    URL_SAFE_NO_PAD.encode(format!("{}:{}", pagination.offset, limit))
+}
+
+fn decode_cursor(cursor: &str) -> Option<Pagination>{
+    let decoded = String::from_utf8(URL_SAFE_NO_PAD.decode(cursor).ok()?).ok()?;
+    let (offset, limit) = decoded.split_once(':')?;
+
+    Some(Pagination{
+        offset: offset.parse().ok()?,
+        limit: if limit.is_empty() { None } else { Some(limit.parse().ok()?) },
+    })
+}
@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Arc;
+use chrono::{DateTime, Utc};
+use rocket::tokio;
+use crate::changes::ChangeEvent;
+use crate::config::{AppConfig, PersistenceKind};
+use crate::history::LieferengpassHistory;
+use crate::lieferengpaesse::Lieferengpass;
+use crate::rote_hand_briefe::Brief;
+use crate::webhooks::Webhook;
+use crate::TempStorage;
+
+pub mod memory;
+pub mod sqlite;
+
+pub type PersistenceError = Box<dyn Error + Send + Sync>;
+
+/// Everything that is loaded on startup.
+#[derive(Default, Clone)]
+pub struct Snapshot{
+    pub lieferengpaesse: Vec<Lieferengpass>,
+    pub lieferengpaesse_refreshed_at: Option<DateTime<Utc>>,
+    pub lieferengpaesse_history: HashMap<String, LieferengpassHistory>,
+    pub briefe: HashMap<String, Brief>,
+    pub briefe_refreshed_at: Option<DateTime<Utc>>,
+    pub changes: Vec<ChangeEvent>,
+    pub changes_log_start: Option<DateTime<Utc>>,
+    pub webhooks: Vec<Webhook>,
+}
+
+/// Storage backend the in-memory data is loaded from on startup and written to after each refresh.
+pub trait Persistence: Send + Sync{
+    fn load(&self) -> Result<Snapshot, PersistenceError>;
+    /// Replaces all stored Lieferengpässe.
+    fn save_lieferengpaesse(&self, lieferengpaesse: &[Lieferengpass], refreshed_at: Option<DateTime<Utc>>) -> Result<(), PersistenceError>;
+    /// Inserts or updates the given histories and appends their versions to the stored ones.
+    /// Only the versions that are not stored yet are passed, see [`LieferengpassHistory::unpersisted`].
+    fn save_history(&self, histories: &[LieferengpassHistory]) -> Result<(), PersistenceError>;
+    /// Replaces all stored letters.
+    fn save_briefe(&self, briefe: &HashMap<String, Brief>, refreshed_at: Option<DateTime<Utc>>) -> Result<(), PersistenceError>;
+    /// Replaces the stored change log.
+    fn save_changes(&self, changes: &[ChangeEvent], log_start: Option<DateTime<Utc>>) -> Result<(), PersistenceError>;
+    /// Replaces the stored webhooks.
+    fn save_webhooks(&self, webhooks: &[Webhook]) -> Result<(), PersistenceError>;
+}
+
+pub fn open(config: &AppConfig) -> Result<Arc<dyn Persistence>, PersistenceError>{
+    Ok(match config.persistence{
+        PersistenceKind::Memory => Arc::new(memory::MemoryPersistence::default()),
+        PersistenceKind::Sqlite => Arc::new(sqlite::SqlitePersistence::open(&config.database)?),
+    })
+}
+
+pub async fn save_lieferengpaesse(storage: Arc<TempStorage>){
+    let (lieferengpaesse, refreshed_at, history) = {
+        let handle = storage.storage.read().await;
+        let history: Vec<LieferengpassHistory> = handle.lieferengpaesse_history.values().map(LieferengpassHistory::unpersisted).collect();
+        (handle.lieferengpaesse.clone(), handle.lieferengpaesse_refreshed_at, history)
+    };
+    let saved_versions: Vec<(String, usize)> = history.iter()
+        .map(|history| (history.bearbeitungsnummer.clone(), history.versions.len()))
+        .collect();
+    let persistence = storage.persistence.clone();
+
+    let result = tokio::task::spawn_blocking(move || {
+        persistence.save_lieferengpaesse(&lieferengpaesse, refreshed_at)?;
+        persistence.save_history(&history)
+    }).await;
+    match result{
+        Ok(Ok(())) => {
+            let mut handle = storage.storage.write().await;
+            for (bearbeitungsnummer, count) in saved_versions{
+                if let Some(history) = handle.lieferengpaesse_history.get_mut(&bearbeitungsnummer){
+                    history.persisted_versions += count;
+                }
+            }
+        },
+        Ok(Err(e)) => tracing::error!(error = %e, "Failed to save Lieferengpässe"),
+        Err(e) => tracing::error!(error = %e, "Failed to save Lieferengpässe"),
+    }
+}
+
+pub async fn save_briefe(storage: Arc<TempStorage>){
+    let (briefe, refreshed_at) = {
+        let handle = storage.storage.read().await;
+        (handle.briefe.clone(), handle.briefe_refreshed_at)
+    };
+    let persistence = storage.persistence.clone();
+
+    match tokio::task::spawn_blocking(move || persistence.save_briefe(&briefe, refreshed_at)).await{
+        Ok(Ok(())) => {},
+        Ok(Err(e)) => tracing::error!(error = %e, "Failed to save letters"),
+        Err(e) => tracing::error!(error = %e, "Failed to save letters"),
+    }
+}
+
+pub async fn save_changes(storage: Arc<TempStorage>){
+    let (changes, log_start) = {
+        let handle = storage.storage.read().await;
+        (handle.changes.clone(), handle.changes_log_start)
+    };
+    let persistence = storage.persistence.clone();
+
+    match tokio::task::spawn_blocking(move || persistence.save_changes(&changes, log_start)).await{
+        Ok(Ok(())) => {},
+        Ok(Err(e)) => tracing::error!(error = %e, "Failed to save change log"),
+        Err(e) => tracing::error!(error = %e, "Failed to save change log"),
+    }
+}
+
+pub async fn save_webhooks(storage: Arc<TempStorage>){
+    let webhooks = storage.storage.read().await.webhooks.clone();
+    let persistence = storage.persistence.clone();
+
+    match tokio::task::spawn_blocking(move || persistence.save_webhooks(&webhooks)).await{
+        Ok(Ok(())) => {},
+        Ok(Err(e)) => tracing::error!(error = %e, "Failed to save webhooks"),
+        Err(e) => tracing::error!(error = %e, "Failed to save webhooks"),
+    }
+}
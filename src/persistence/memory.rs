@@ -0,0 +1,61 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use chrono::{DateTime, Utc};
+use crate::changes::ChangeEvent;
+use crate::history::LieferengpassHistory;
+use crate::lieferengpaesse::Lieferengpass;
+use crate::persistence::{Persistence, PersistenceError, Snapshot};
+use crate::rote_hand_briefe::Brief;
+use crate::webhooks::Webhook;
+
+/// Keeps the data in memory only, so nothing survives a restart.
+#[derive(Default)]
+pub struct MemoryPersistence{
+    snapshot: Mutex<Snapshot>,
+}
+
+impl Persistence for MemoryPersistence{
+    fn load(&self) -> Result<Snapshot, PersistenceError>{
+        Ok(self.snapshot.lock().map_err(|e| e.to_string())?.clone())
+    }
+
+    fn save_lieferengpaesse(&self, lieferengpaesse: &[Lieferengpass], refreshed_at: Option<DateTime<Utc>>) -> Result<(), PersistenceError>{
+        let mut snapshot = self.snapshot.lock().map_err(|e| e.to_string())?;
+        snapshot.lieferengpaesse = lieferengpaesse.to_vec();
+        snapshot.lieferengpaesse_refreshed_at = refreshed_at;
+        Ok(())
+    }
+
+    fn save_history(&self, histories: &[LieferengpassHistory]) -> Result<(), PersistenceError>{
+        let mut snapshot = self.snapshot.lock().map_err(|e| e.to_string())?;
+        for history in histories{
+            let stored = snapshot.lieferengpaesse_history.entry(history.bearbeitungsnummer.clone())
+                .or_insert_with(|| LieferengpassHistory{ versions: Vec::new(), ..history.clone() });
+            stored.first_seen = history.first_seen;
+            stored.last_seen = history.last_seen;
+            stored.versions.extend(history.versions.iter().cloned());
+            stored.persisted_versions = stored.versions.len();
+        }
+        Ok(())
+    }
+
+    fn save_briefe(&self, briefe: &HashMap<String, Brief>, refreshed_at: Option<DateTime<Utc>>) -> Result<(), PersistenceError>{
+        let mut snapshot = self.snapshot.lock().map_err(|e| e.to_string())?;
+        snapshot.briefe = briefe.clone();
+        snapshot.briefe_refreshed_at = refreshed_at;
+        Ok(())
+    }
+
+    fn save_changes(&self, changes: &[ChangeEvent], log_start: Option<DateTime<Utc>>) -> Result<(), PersistenceError>{
+        let mut snapshot = self.snapshot.lock().map_err(|e| e.to_string())?;
+        snapshot.changes = changes.to_vec();
+        snapshot.changes_log_start = log_start;
+        Ok(())
+    }
+
+    fn save_webhooks(&self, webhooks: &[Webhook]) -> Result<(), PersistenceError>{
+        let mut snapshot = self.snapshot.lock().map_err(|e| e.to_string())?;
+        snapshot.webhooks = webhooks.to_vec();
+        Ok(())
+    }
+}
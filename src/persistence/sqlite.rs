@@ -0,0 +1,446 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use chrono::{DateTime, Utc};
+use rusqlite::types::Type;
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension, Row, ToSql, Transaction};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use crate::changes::ChangeEvent;
+use crate::history::{LieferengpassHistory, LieferengpassVersion};
+use crate::lieferengpaesse::Lieferengpass;
+use crate::persistence::{Persistence, PersistenceError, Snapshot};
+use crate::rote_hand_briefe::Brief;
+use crate::webhooks::Webhook;
+
+/// Schema migrations, applied in order. `PRAGMA user_version` holds the number of applied migrations.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE lieferengpaesse(
+        position INTEGER PRIMARY KEY,
+        pzn INTEGER NOT NULL,
+        enr TEXT NOT NULL,
+        bearbeitungsnummer TEXT NOT NULL,
+        erstmeldung TEXT,
+        erstmeldung_datum TEXT NOT NULL,
+        meldungsart TEXT NOT NULL,
+        beginn TEXT NOT NULL,
+        ende TEXT NOT NULL,
+        letzte_meldung TEXT NOT NULL,
+        art_des_grundes TEXT NOT NULL,
+        arzneimittelbezeichnung TEXT NOT NULL,
+        atc TEXT NOT NULL,
+        wirkstoffe TEXT NOT NULL,
+        kkh_relevant INTEGER NOT NULL,
+        zulassungsinhaber TEXT NOT NULL,
+        grund TEXT NOT NULL,
+        anmerkung_zum_grund TEXT,
+        alternativpraeparat TEXT,
+        info_an_fachkreise TEXT NOT NULL,
+        darreichungsform TEXT NOT NULL,
+        klassifikation TEXT NOT NULL
+    );
+    CREATE TABLE briefe(
+        link_to_html TEXT PRIMARY KEY,
+        id TEXT NOT NULL,
+        letter_type TEXT NOT NULL,
+        source TEXT NOT NULL,
+        date TEXT NOT NULL,
+        title TEXT NOT NULL,
+        wirkstoffe TEXT,
+        link_to_pdf TEXT NOT NULL,
+        short_description TEXT,
+        long_description TEXT
+    );
+    CREATE TABLE refreshes(
+        dataset TEXT PRIMARY KEY,
+        refreshed_at TEXT NOT NULL
+    );",
+    "CREATE TABLE lieferengpass_history(
+        bearbeitungsnummer TEXT PRIMARY KEY,
+        first_seen TEXT NOT NULL,
+        last_seen TEXT NOT NULL
+    );
+    CREATE TABLE lieferengpass_versions(
+        seen_at TEXT NOT NULL,
+        changes TEXT NOT NULL,
+        pzn INTEGER NOT NULL,
+        enr TEXT NOT NULL,
+        bearbeitungsnummer TEXT NOT NULL,
+        erstmeldung TEXT,
+        erstmeldung_datum TEXT NOT NULL,
+        meldungsart TEXT NOT NULL,
+        beginn TEXT NOT NULL,
+        ende TEXT NOT NULL,
+        letzte_meldung TEXT NOT NULL,
+        art_des_grundes TEXT NOT NULL,
+        arzneimittelbezeichnung TEXT NOT NULL,
+        atc TEXT NOT NULL,
+        wirkstoffe TEXT NOT NULL,
+        kkh_relevant INTEGER NOT NULL,
+        zulassungsinhaber TEXT NOT NULL,
+        grund TEXT NOT NULL,
+        anmerkung_zum_grund TEXT,
+        alternativpraeparat TEXT,
+        info_an_fachkreise TEXT NOT NULL,
+        darreichungsform TEXT NOT NULL,
+        klassifikation TEXT NOT NULL,
+        UNIQUE(bearbeitungsnummer, seen_at)
+    );",
+    "ALTER TABLE lieferengpaesse ADD COLUMN vanished_at TEXT;
+    ALTER TABLE lieferengpass_versions ADD COLUMN vanished_at TEXT;",
+    "CREATE TABLE changes(
+        at TEXT NOT NULL,
+        dataset TEXT NOT NULL,
+        id TEXT NOT NULL,
+        kind TEXT NOT NULL
+    );
+    CREATE TABLE meta(
+        key TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );",
+    "CREATE TABLE webhooks(
+        id TEXT PRIMARY KEY,
+        url TEXT NOT NULL,
+        secret TEXT NOT NULL,
+        filter TEXT NOT NULL,
+        created_at TEXT NOT NULL
+    );",
+    "ALTER TABLE briefe ADD COLUMN full_text TEXT;",
+    // Enum values were stored without quotes, which made an unknown value like `123` read back as a number
+    "UPDATE lieferengpaesse SET meldungsart = json_quote(meldungsart), art_des_grundes = json_quote(art_des_grundes),
+        info_an_fachkreise = json_quote(info_an_fachkreise), klassifikation = json_quote(klassifikation);
+    UPDATE lieferengpass_versions SET meldungsart = json_quote(meldungsart), art_des_grundes = json_quote(art_des_grundes),
+        info_an_fachkreise = json_quote(info_an_fachkreise), klassifikation = json_quote(klassifikation);
+    UPDATE briefe SET letter_type = json_quote(letter_type), source = json_quote(source);
+    UPDATE changes SET dataset = json_quote(dataset), kind = json_quote(kind);",
+];
+
+const LIEFERENGPASS_COLUMNS: &str = "pzn, enr, bearbeitungsnummer, erstmeldung, erstmeldung_datum, meldungsart, beginn, ende, letzte_meldung, art_des_grundes, arzneimittelbezeichnung, atc, wirkstoffe, kkh_relevant, zulassungsinhaber, grund, anmerkung_zum_grund, alternativpraeparat, info_an_fachkreise, darreichungsform, klassifikation, vanished_at";
+
+pub struct SqlitePersistence{
+    connection: Mutex<Connection>,
+}
+
+impl SqlitePersistence{
+    pub fn open(path: &str) -> Result<Self, PersistenceError>{
+        let mut connection = Connection::open(path)?;
+        migrate(&mut connection)?;
+
+        Ok(SqlitePersistence{
+            connection: Mutex::new(connection),
+        })
+    }
+}
+
+fn migrate(connection: &mut Connection) -> rusqlite::Result<()>{
+    let applied: usize = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+    for (version, migration) in MIGRATIONS.iter().enumerate().skip(applied){
+        let tx = connection.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", version + 1)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+/// Stores enums and lists as JSON the way serde names them, so the database matches the JSON output. Strings are
+/// quoted as well, otherwise a kept unknown value like `123` couldn't be told apart from a number.
+fn to_json<T: Serialize>(value: &T) -> rusqlite::Result<String>{
+    serde_json::to_string(value).map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))
+}
+
+fn from_json<T: DeserializeOwned>(row: &Row, column: &str) -> rusqlite::Result<T>{
+    let text: String = row.get(column)?;
+    let index = row.as_ref().column_index(column)?;
+    serde_json::from_str(&text).map_err(|e| rusqlite::Error::FromSqlConversionFailure(index, Type::Text, Box::new(e)))
+}
+
+fn placeholders(count: usize) -> String{
+    (1..=count).map(|i| format!("?{}", i)).collect::<Vec<String>>().join(", ")
+}
+
+fn lieferengpass_values(entry: &Lieferengpass) -> rusqlite::Result<Vec<Box<dyn ToSql>>>{
+    Ok(vec![
+        Box::new(entry.pzn),
+        Box::new(to_json(&entry.enr)?),
+        Box::new(entry.bearbeitungsnummer.clone()),
+        Box::new(entry.erstmeldung.clone()),
+        Box::new(entry.erstmeldung_datum),
+        Box::new(to_json(&entry.meldungsart)?),
+        Box::new(entry.beginn),
+        Box::new(entry.ende),
+        Box::new(entry.letzte_meldung),
+        Box::new(to_json(&entry.art_des_grundes)?),
+        Box::new(entry.arzneimittelbezeichnung.clone()),
+        Box::new(entry.atc.clone()),
+        Box::new(entry.wirkstoffe.clone()),
+        Box::new(entry.kkh_relevant),
+        Box::new(entry.zulassungsinhaber.clone()),
+        Box::new(entry.grund.clone()),
+        Box::new(entry.anmerkung_zum_grund.clone()),
+        Box::new(entry.alternativpraeparat.clone()),
+        Box::new(to_json(&entry.info_an_fachkreise)?),
+        Box::new(entry.darreichungsform.clone()),
+        Box::new(to_json(&entry.klassifikation)?),
+        Box::new(entry.vanished_at),
+    ])
+}
+
+fn lieferengpass_from_row(row: &Row) -> rusqlite::Result<Lieferengpass>{
+    Ok(Lieferengpass{
+        pzn: row.get("pzn")?,
+        enr: from_json(row, "enr")?,
+        bearbeitungsnummer: row.get("bearbeitungsnummer")?,
+        erstmeldung: row.get("erstmeldung")?,
+        erstmeldung_datum: row.get("erstmeldung_datum")?,
+        meldungsart: from_json(row, "meldungsart")?,
+        beginn: row.get("beginn")?,
+        ende: row.get("ende")?,
+        letzte_meldung: row.get("letzte_meldung")?,
+        art_des_grundes: from_json(row, "art_des_grundes")?,
+        arzneimittelbezeichnung: row.get("arzneimittelbezeichnung")?,
+        atc: row.get("atc")?,
+        wirkstoffe: row.get("wirkstoffe")?,
+        wirkstoffe_normalized: Vec::new(),
+        kkh_relevant: row.get("kkh_relevant")?,
+        zulassungsinhaber: row.get("zulassungsinhaber")?,
+        grund: row.get("grund")?,
+        anmerkung_zum_grund: row.get("anmerkung_zum_grund")?,
+        alternativpraeparat: row.get("alternativpraeparat")?,
+        info_an_fachkreise: from_json(row, "info_an_fachkreise")?,
+        darreichungsform: row.get("darreichungsform")?,
+        klassifikation: from_json(row, "klassifikation")?,
+        status: Default::default(),
+        vanished_at: row.get("vanished_at")?,
+        folgemeldungen: Vec::new(),
+        related_briefe: Vec::new(),
+    })
+}
+
+fn brief_from_row(row: &Row) -> rusqlite::Result<Brief>{
+    let wirkstoffe: Option<String> = row.get("wirkstoffe")?;
+    let wirkstoffe = match wirkstoffe{
+        Some(_) => Some(from_json(row, "wirkstoffe")?),
+        None => None,
+    };
+
+    Ok(Brief{
+        id: row.get("id")?,
+        letter_type: from_json(row, "letter_type")?,
+        source: from_json(row, "source")?,
+        date: row.get("date")?,
+        title: row.get("title")?,
+        wirkstoffe,
+        wirkstoffe_normalized: Vec::new(),
+        link_to_html: row.get("link_to_html")?,
+        link_to_pdf: row.get("link_to_pdf")?,
+        short_description: row.get("short_description")?,
+        long_description: row.get("long_description")?,
+        full_text: row.get("full_text")?,
+        related_lieferengpaesse: Vec::new(),
+    })
+}
+
+fn load_history(connection: &Connection) -> rusqlite::Result<HashMap<String, LieferengpassHistory>>{
+    let mut histories = connection.prepare("SELECT * FROM lieferengpass_history")?
+        .query_map([], |row| {
+            Ok(LieferengpassHistory{
+                bearbeitungsnummer: row.get("bearbeitungsnummer")?,
+                first_seen: row.get("first_seen")?,
+                last_seen: row.get("last_seen")?,
+                versions: Vec::new(),
+                persisted_versions: 0,
+            })
+        })?
+        .map(|history| history.map(|history| (history.bearbeitungsnummer.clone(), history)))
+        .collect::<rusqlite::Result<HashMap<String, LieferengpassHistory>>>()?;
+
+    let mut statement = connection.prepare("SELECT * FROM lieferengpass_versions ORDER BY seen_at")?;
+    let versions = statement.query_map([], |row| {
+        Ok(LieferengpassVersion{
+            seen_at: row.get("seen_at")?,
+            changes: from_json(row, "changes")?,
+            record: lieferengpass_from_row(row)?,
+        })
+    })?;
+    for version in versions{
+        let version = version?;
+        if let Some(history) = histories.get_mut(&version.record.bearbeitungsnummer){
+            history.versions.push(version);
+            history.persisted_versions += 1;
+        }
+    }
+
+    Ok(histories)
+}
+
+fn load_changes(connection: &Connection) -> rusqlite::Result<Vec<ChangeEvent>>{
+    connection.prepare("SELECT * FROM changes ORDER BY rowid")?
+        .query_map([], |row| {
+            Ok(ChangeEvent{
+                at: row.get("at")?,
+                dataset: from_json(row, "dataset")?,
+                id: row.get("id")?,
+                kind: from_json(row, "kind")?,
+            })
+        })?
+        .collect()
+}
+
+fn load_webhooks(connection: &Connection) -> rusqlite::Result<Vec<Webhook>>{
+    connection.prepare("SELECT * FROM webhooks ORDER BY created_at")?
+        .query_map([], |row| {
+            Ok(Webhook{
+                id: row.get("id")?,
+                url: row.get("url")?,
+                secret: row.get("secret")?,
+                filter: from_json(row, "filter")?,
+                created_at: row.get("created_at")?,
+            })
+        })?
+        .collect()
+}
+
+fn refreshed_at(connection: &Connection, dataset: &str) -> rusqlite::Result<Option<DateTime<Utc>>>{
+    connection.query_row("SELECT refreshed_at FROM refreshes WHERE dataset = ?1", [dataset], |row| row.get(0)).optional()
+}
+
+fn set_refreshed_at(tx: &Transaction, dataset: &str, refreshed_at: Option<DateTime<Utc>>) -> rusqlite::Result<()>{
+    if let Some(refreshed_at) = refreshed_at{
+        tx.execute("INSERT OR REPLACE INTO refreshes(dataset, refreshed_at) VALUES (?1, ?2)", params![dataset, refreshed_at])?;
+    }
+    Ok(())
+}
+
+impl Persistence for SqlitePersistence{
+    fn load(&self) -> Result<Snapshot, PersistenceError>{
+        let connection = self.connection.lock().map_err(|e| e.to_string())?;
+
+        let lieferengpaesse = connection.prepare("SELECT * FROM lieferengpaesse ORDER BY position")?
+            .query_map([], lieferengpass_from_row)?
+            .collect::<rusqlite::Result<Vec<Lieferengpass>>>()?;
+
+        let briefe = connection.prepare("SELECT * FROM briefe")?
+            .query_map([], brief_from_row)?
+            .map(|brief| brief.map(|brief| (brief.link_to_html.clone(), brief)))
+            .collect::<rusqlite::Result<HashMap<String, Brief>>>()?;
+
+        Ok(Snapshot{
+            lieferengpaesse,
+            lieferengpaesse_refreshed_at: refreshed_at(&connection, "lieferengpaesse")?,
+            lieferengpaesse_history: load_history(&connection)?,
+            briefe,
+            briefe_refreshed_at: refreshed_at(&connection, "briefe")?,
+            changes: load_changes(&connection)?,
+            webhooks: load_webhooks(&connection)?,
+            changes_log_start: connection.query_row("SELECT value FROM meta WHERE key = 'changes_log_start'", [], |row| row.get(0)).optional()?,
+        })
+    }
+
+    fn save_lieferengpaesse(&self, lieferengpaesse: &[Lieferengpass], refreshed_at: Option<DateTime<Utc>>) -> Result<(), PersistenceError>{
+        let mut connection = self.connection.lock().map_err(|e| e.to_string())?;
+        let tx = connection.transaction()?;
+
+        tx.execute("DELETE FROM lieferengpaesse", [])?;
+        {
+            let mut statement = tx.prepare(&format!("INSERT INTO lieferengpaesse(position, {}) VALUES ({})", LIEFERENGPASS_COLUMNS, placeholders(LIEFERENGPASS_COLUMNS.split(',').count() + 1)))?;
+            for (position, entry) in lieferengpaesse.iter().enumerate(){
+                let mut values: Vec<Box<dyn ToSql>> = vec![Box::new(position)];
+                values.append(&mut lieferengpass_values(entry)?);
+                statement.execute(params_from_iter(values))?;
+            }
+        }
+        set_refreshed_at(&tx, "lieferengpaesse", refreshed_at)?;
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn save_history(&self, histories: &[LieferengpassHistory]) -> Result<(), PersistenceError>{
+        let mut connection = self.connection.lock().map_err(|e| e.to_string())?;
+        let tx = connection.transaction()?;
+
+        {
+            let mut history_statement = tx.prepare("INSERT OR REPLACE INTO lieferengpass_history(bearbeitungsnummer, first_seen, last_seen) VALUES (?1, ?2, ?3)")?;
+            let mut version_statement = tx.prepare(&format!("INSERT OR IGNORE INTO lieferengpass_versions(seen_at, changes, {}) VALUES ({})", LIEFERENGPASS_COLUMNS, placeholders(LIEFERENGPASS_COLUMNS.split(',').count() + 2)))?;
+
+            for history in histories{
+                history_statement.execute(params![history.bearbeitungsnummer, history.first_seen, history.last_seen])?;
+
+                for version in &history.versions{
+                    let mut values: Vec<Box<dyn ToSql>> = vec![Box::new(version.seen_at), Box::new(to_json(&version.changes)?)];
+                    values.append(&mut lieferengpass_values(&version.record)?);
+                    version_statement.execute(params_from_iter(values))?;
+                }
+            }
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn save_briefe(&self, briefe: &HashMap<String, Brief>, refreshed_at: Option<DateTime<Utc>>) -> Result<(), PersistenceError>{
+        let mut connection = self.connection.lock().map_err(|e| e.to_string())?;
+        let tx = connection.transaction()?;
+
+        tx.execute("DELETE FROM briefe", [])?;
+        {
+            let mut statement = tx.prepare("INSERT INTO briefe(link_to_html, id, letter_type, source, date, title, wirkstoffe, link_to_pdf, short_description, long_description, full_text) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)")?;
+            for brief in briefe.values(){
+                let wirkstoffe = brief.wirkstoffe.as_ref().map(to_json).transpose()?;
+                statement.execute(params![
+                    brief.link_to_html,
+                    brief.id,
+                    to_json(&brief.letter_type)?,
+                    to_json(&brief.source)?,
+                    brief.date,
+                    brief.title,
+                    wirkstoffe,
+                    brief.link_to_pdf,
+                    brief.short_description,
+                    brief.long_description,
+                    brief.full_text,
+                ])?;
+            }
+        }
+        set_refreshed_at(&tx, "briefe", refreshed_at)?;
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn save_changes(&self, changes: &[ChangeEvent], log_start: Option<DateTime<Utc>>) -> Result<(), PersistenceError>{
+        let mut connection = self.connection.lock().map_err(|e| e.to_string())?;
+        let tx = connection.transaction()?;
+
+        tx.execute("DELETE FROM changes", [])?;
+        {
+            let mut statement = tx.prepare("INSERT INTO changes(at, dataset, id, kind) VALUES (?1, ?2, ?3, ?4)")?;
+            for event in changes{
+                statement.execute(params![event.at, to_json(&event.dataset)?, event.id, to_json(&event.kind)?])?;
+            }
+        }
+        if let Some(log_start) = log_start{
+            tx.execute("INSERT OR REPLACE INTO meta(key, value) VALUES ('changes_log_start', ?1)", [log_start])?;
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn save_webhooks(&self, webhooks: &[Webhook]) -> Result<(), PersistenceError>{
+        let mut connection = self.connection.lock().map_err(|e| e.to_string())?;
+        let tx = connection.transaction()?;
+
+        tx.execute("DELETE FROM webhooks", [])?;
+        {
+            let mut statement = tx.prepare("INSERT INTO webhooks(id, url, secret, filter, created_at) VALUES (?1, ?2, ?3, ?4, ?5)")?;
+            for webhook in webhooks{
+                statement.execute(params![webhook.id, webhook.url, webhook.secret, to_json(&webhook.filter)?, webhook.created_at])?;
+            }
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+}
@@ -1,64 +1,154 @@
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
 use std::sync::Arc;
-use chrono::NaiveDate;
+use chrono::{DateTime, NaiveDate, Utc};
 use regex::Regex;
-use reqwest::Error;
 use rocket::form::validate::Contains;
-use rocket::futures::future::{join_all, try_join_all};
-use rocket::serde::Serialize;
-use rocket::tokio::join;
-use crate::TempStorage;
+use rocket::futures::future::join_all;
+use rocket::serde::{Deserialize, Serialize};
+use crate::changes::{ChangeEvent, ChangeKind, Dataset};
+use crate::status::{CrawlReport, Source};
+use crate::{changes, metrics, wirkstoffe, InnerStorage, TempStorage};
+use rocket::tokio;
 use scraper::*;
+use sha2::{Digest, Sha256};
+use tracing::Instrument;
 
 const MAX_CONCURRENT_REQUESTS: u8 = 5;
 
 #[derive(Debug, Clone, Serialize)]
 pub struct Brief{
+    /// Stable, URL-safe id derived from `link_to_html`.
+    pub id: String,
     pub letter_type: LetterType,
     pub source: LetterSource,
     pub date: NaiveDate,
     pub title: String,
     pub wirkstoffe: Option<Vec<String>>,
+    /// `wirkstoffe` without strengths and salt forms, see [`crate::wirkstoffe`].
+    pub wirkstoffe_normalized: Vec<String>,
     pub link_to_html: String,
     pub link_to_pdf: String,
     pub short_description: Option<String>,
     pub long_description: Option<String>,
+    /// Text extracted from the PDF, `None` until it was downloaded.
+    pub full_text: Option<String>,
+    /// Bearbeitungsnummern of active Lieferengpässe on the same Wirkstoff.
+    pub related_lieferengpaesse: Vec<String>,
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub enum LetterType{
     RoteHandBrief,
     Informationsbrief
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub enum LetterSource{
     BfArM,
     PEI
 }
-pub async fn crawl_bfarm(storage: Arc<TempStorage>) -> Result<(), reqwest::Error> {
+
+/// Derives the letter id from the link to the letter, so it stays the same across crawls and restarts.
+pub fn letter_id(link_to_html: &str) -> String{
+    let hash = format!("{:x}", Sha256::digest(link_to_html.as_bytes()));
+    hash[..16].to_string()
+}
+
+impl Brief{
+    /// Hash over the fields read from the listing or, for PEI letters, the letter page. A letter whose fingerprint
+    /// changed is crawled again and reported as modified. The long description, the full text and the Wirkstoffe of
+    /// PEI letters are filled in later and left out.
+    pub fn fingerprint(&self) -> String{
+        let wirkstoffe = match self.source{
+            LetterSource::BfArM => self.wirkstoffe.as_ref(),
+            LetterSource::PEI => None,
+        };
+        let fields = (&self.letter_type, self.date, &self.title, &self.link_to_pdf, &self.short_description, wirkstoffe);
+        format!("{:x}", Sha256::digest(serde_json::to_vec(&fields).unwrap_or_default()))
+    }
+}
+
+/// Stores a crawled letter, replacing the known one with the same link. A replaced letter gets its full text extracted again.
+fn store(storage: &mut InnerStorage, brief: Brief, now: DateTime<Utc>) -> ChangeEvent{
+    storage.full_text_failed.remove(&brief.link_to_html);
+    let id = brief.id.clone();
+    let kind = match storage.briefe.insert(brief.link_to_html.clone(), brief){
+        Some(_) => ChangeKind::Modified,
+        None => ChangeKind::Added,
+    };
+    ChangeEvent{ at: now, dataset: Dataset::Briefe, id, kind }
+}
+
+/// Removes the letters of `source` that are no longer listed. Nothing is removed if the listing was empty, that's more
+/// likely an upstream error than all letters being withdrawn.
+fn remove_unlisted(storage: &mut InnerStorage, source: LetterSource, listed: &HashSet<String>, now: DateTime<Utc>) -> Vec<ChangeEvent>{
+    if listed.is_empty(){
+        tracing::warn!("Listing is empty, keeping the known letters");
+        return Vec::new();
+    }
+
+    let unlisted: Vec<String> = storage.briefe.values()
+        .filter(|brief| brief.source == source && !listed.contains(&brief.link_to_html))
+        .map(|brief| brief.link_to_html.clone())
+        .collect();
+    unlisted.into_iter()
+        .filter_map(|link_to_html| {
+            storage.full_text_failed.remove(&link_to_html);
+            let brief = storage.briefe.remove(&link_to_html)?;
+            tracing::info!(url = %link_to_html, record_id = %brief.id, "Letter is no longer listed");
+            Some(ChangeEvent{ at: now, dataset: Dataset::Briefe, id: brief.id, kind: ChangeKind::Removed })
+        })
+        .collect()
+}
+
+#[tracing::instrument(name = "crawl", skip_all, fields(source = "bfarm"))]
+pub async fn crawl_bfarm(storage: Arc<TempStorage>) -> Result<CrawlReport, reqwest::Error> {
     let client = storage.storage.read().await.reqwest_client.clone();
+    let bfarm_base_url = storage.config.bfarm_base_url.trim_end_matches('/');
+    let mut report = CrawlReport::default();
 
     let mut page = 1;
     let mut briefe: Vec<Brief> = Vec::new();
+    // Links of all rows, including the ones skipped, so their letters aren't removed
+    let mut listed: HashSet<String> = HashSet::new();
+    // Whether the listing ended on the page past the last entries, only then unlisted letters are removed
+    let mut complete = false;
 
     loop {
-        let mut any_entry_added = false;
-        println!("Getting page {}", page);
-        let request = client.get(format!("https://www.bfarm.de/DE/Arzneimittel/Pharmakovigilanz/Risikoinformationen/Rote-Hand-Briefe/_node.html?cms_gtp=964792_list%253D{}", page)).build()?;
-        let response = client.execute(request).await?;
-
-        let fragment = Html::parse_fragment(&response.text().await?);
+        let mut any_letter_listed = false;
+        let url = format!("{}/DE/Arzneimittel/Pharmakovigilanz/Risikoinformationen/Rote-Hand-Briefe/_node.html?cms_gtp=964792_list%253D{}", bfarm_base_url, page);
+        let span = tracing::info_span!("page", page, %url);
+        let request = client.get(&url).build()?;
+        let response = metrics::observe_upstream(Source::Bfarm, client.execute(request).instrument(span.clone()).await)
+            .and_then(|response| response.error_for_status())?;
+        let html = response.text().instrument(span.clone()).await?;
+        // Parsing the page doesn't await, the span is left at the end of the iteration
+        let _entered = span.enter();
+        tracing::debug!("Parsing page");
+
+        let fragment = Html::parse_fragment(&html);
+
+        // Past the last entries the listing page is still served, just without the table
+        let heading_selector = Selector::parse("h1").unwrap();
+        let is_listing = fragment.select(&heading_selector).any(|heading| heading.text().collect::<String>().contains("Rote-Hand-Briefe"));
 
         let table_selector = Selector::parse("table").unwrap();
         let table = match fragment.select(&table_selector).next() {
             Some(table) => table,
-            None => break,
+            None => {
+                complete = is_listing;
+                break;
+            }
         };
 
         let rows_selector = Selector::parse("tr").unwrap();
         let rows: Vec<ElementRef> = table.select(&rows_selector).collect();
 
-        if rows.is_empty() { break; }
+        if rows.is_empty() {
+            complete = is_listing;
+            break;
+        }
 
         let td_selector = Selector::parse("td").unwrap();
         let a_selector = Selector::parse("a").unwrap();
@@ -67,28 +157,25 @@ pub async fn crawl_bfarm(storage: Arc<TempStorage>) -> Result<(), reqwest::Error
         for row in &rows {
             let tds: Vec<ElementRef> = row.select(&td_selector).collect();
 
+            // Header rows only have th cells
+            if tds.is_empty() {
+                continue;
+            }
             if tds.len() != 2 {
-                eprintln!("Expected tr to have two columns. Skipping.");
+                tracing::warn!(columns = tds.len(), "Expected tr to have two columns, skipping");
+                report.skip("row without two columns");
                 continue;
             }
 
             let mut iter = tds.iter();
-
-            let date = iter.next().unwrap().text().collect::<String>().trim().to_string();
-            let date = match NaiveDate::parse_from_str(date.as_str(), "%d.%m.%Y") {
-                Ok(date) => date,
-                Err(e) => {
-                    eprintln!("Couldn't parse letter date: {}", e);
-                    continue;
-                }
-            };
-
+            let datecol = iter.next().unwrap();
             let datacol = iter.next().unwrap();
 
             let link = match datacol.select(&a_selector).next() {
                 Some(link) => link,
                 None => {
-                    eprintln!("Expected a link in the data row :( Skipping.");
+                    tracing::warn!("Expected a link in the data row, skipping");
+                    report.skip("row without link");
                     continue;
                 }
             };
@@ -96,7 +183,8 @@ pub async fn crawl_bfarm(storage: Arc<TempStorage>) -> Result<(), reqwest::Error
             let link_to_letter = match link.value().attr("href") {
                 Some(href) => href,
                 None => {
-                    eprintln!("Link has no href attribute. Skipping.");
+                    tracing::warn!("Link has no href attribute, skipping");
+                    report.skip("link without href");
                     continue;
                 }
             };
@@ -104,11 +192,24 @@ pub async fn crawl_bfarm(storage: Arc<TempStorage>) -> Result<(), reqwest::Error
             let base_url = match link_to_letter.split(".html").next() {
                 Some(url) => url,
                 None => {
-                    eprintln!("Could not split link URL. Skipping.");
+                    tracing::warn!(href = link_to_letter, "Could not split link URL, skipping");
+                    report.skip("invalid link");
+                    continue;
+                }
+            };
+            let link_to_letter = format!("{}/{}", bfarm_base_url, base_url);
+            listed.insert(link_to_letter.clone());
+            any_letter_listed = true;
+
+            let date = datecol.text().collect::<String>().trim().to_string();
+            let date = match NaiveDate::parse_from_str(date.as_str(), "%d.%m.%Y") {
+                Ok(date) => date,
+                Err(e) => {
+                    tracing::warn!(url = %link_to_letter, %date, error = %e, "Couldn't parse letter date, skipping");
+                    report.skip("invalid date");
                     continue;
                 }
             };
-            let link_to_letter = format!("https://www.bfarm.de/{}", base_url);
 
             let link_to_pdf = format!("{}?__blob=publicationFile", link_to_letter);
             let title = link.inner_html();
@@ -116,7 +217,8 @@ pub async fn crawl_bfarm(storage: Arc<TempStorage>) -> Result<(), reqwest::Error
             let p_tag = match datacol.select(&teasertext_selector).next() {
                 Some(p_tag) => p_tag,
                 None => {
-                    eprintln!("Expected a p tag in the data row :( Skipping.");
+                    tracing::warn!(url = %link_to_letter, "Expected a p tag in the data row, skipping");
+                    report.skip("row without teaser text");
                     continue;
                 }
             };
@@ -135,25 +237,25 @@ pub async fn crawl_bfarm(storage: Arc<TempStorage>) -> Result<(), reqwest::Error
                                 let el_ref = match ElementRef::wrap(child) {
                                     Some(el) => el,
                                     None => {
-                                        eprintln!("Could not wrap element ref. Skipping wirkstoff.");
+                                        tracing::warn!(url = %link_to_letter, "Could not wrap element ref, skipping Wirkstoff");
                                         continue;
                                     }
                                 };
                                 let mut span_text = el_ref.text().collect::<String>();
                                 span_text = match span_text.split("Wirkstoff:").last() {
                                     None => {
-                                        eprintln!("No Wirkstoff found in span.");
+                                        tracing::warn!(url = %link_to_letter, "No Wirkstoff found in span");
                                         continue;
                                     }
                                     Some(wirkstoff) => wirkstoff.to_string(),
                                 };
-                                wirkstoffe = span_text.split(|c| c == ',' || c == '/').map(|ele| ele.trim().to_string()).collect();
+                                wirkstoffe = wirkstoffe::split(&span_text);
                             }
                         } else {
                             let el_ref = match ElementRef::wrap(child) {
                                 Some(el) => el,
                                 None => {
-                                    eprintln!("Could not wrap element ref. Skipping description part.");
+                                    tracing::warn!(url = %link_to_letter, "Could not wrap element ref, skipping description part");
                                     continue;
                                 }
                             };
@@ -174,68 +276,87 @@ pub async fn crawl_bfarm(storage: Arc<TempStorage>) -> Result<(), reqwest::Error
             };
 
             let brief = Brief {
+                id: letter_id(&link_to_letter),
                 letter_type,
                 source: LetterSource::BfArM,
                 date,
                 title,
                 wirkstoffe: Some(wirkstoffe),
+                wirkstoffe_normalized: Vec::new(),
                 link_to_html: link_to_letter,
                 link_to_pdf,
                 short_description: Some(short_description),
                 long_description: None,
+                full_text: None,
+                related_lieferengpaesse: Vec::new(),
             };
             briefe.push(brief);
-            any_entry_added = true;
         }
 
-        if !any_entry_added {
+        if !any_letter_listed {
             break;
         }
 
-        page = page + 1;
+        page += 1;
     }
 
-    let mut briefe_to_crawl = Vec::<Brief>::new();
-
-    for brief in briefe {
-        if !storage.storage.read().await.briefe.contains_key(&brief.link_to_html) {
-            briefe_to_crawl.push(brief);
-        }
-    }
+    report.parsed = briefe.len();
+    // New letters and the ones changed in the listing
+    let mut briefe_to_crawl: Vec<Brief> = {
+        let handle = storage.storage.read().await;
+        briefe.into_iter()
+            .filter(|brief| handle.briefe.get(&brief.link_to_html).is_none_or(|known| known.fingerprint() != brief.fingerprint()))
+            .collect()
+    };
 
     let mut briefe_res: Vec<Brief> = Vec::new();
     
-    println!("Crawling description for {} BfArM letters", briefe_to_crawl.len());
+    tracing::info!(letters = briefe_to_crawl.len(), "Crawling letter descriptions");
 
     while !briefe_to_crawl.is_empty() {
         let chunk_size = briefe_to_crawl.len().min(MAX_CONCURRENT_REQUESTS as usize);
         let mut next_briefe: Vec<Brief> = if briefe_to_crawl.len() >= MAX_CONCURRENT_REQUESTS as usize {
             briefe_to_crawl.drain(..chunk_size).collect()
         }else{
-            briefe_to_crawl.drain(..).collect()
+            std::mem::take(&mut briefe_to_crawl)
         };
 
         let futures = next_briefe.iter_mut().map(|brief| bfarm_crawl_detailed_entry(brief, client.clone())).collect::<Vec<_>>();
-        join_all(futures).await;
+        for result in join_all(futures).await{
+            if let Err(e) = result{
+                tracing::warn!(error = %e, "Failed to crawl letter description");
+            }
+        }
 
         briefe_res.append(&mut next_briefe);
-        println!("Crawled next chunk, now {} briefe are finished.", briefe_res.len());
+        tracing::debug!(finished = briefe_res.len(), "Crawled next chunk");
     }
 
     // Add to storage
+    let now = Utc::now();
     let mut handle = storage.storage.write().await;
+    let mut events = Vec::new();
     for brief in briefe_res {
-        handle.briefe.insert(brief.link_to_html.clone(), brief);
+        events.push(store(&mut handle, brief, now));
     }
+    if complete {
+        events.extend(remove_unlisted(&mut handle, LetterSource::BfArM, &listed, now));
+    } else {
+        tracing::warn!(page, "Listing ended on an unexpected page, keeping the unlisted letters");
+    }
+    changes::log(&mut handle, events);
+    handle.briefe_refreshed_at = Some(now);
+    handle.update_derived_data();
 
-    println!("Finished crawl!");
+    tracing::info!(parsed = report.parsed, skipped = report.skipped.values().sum::<usize>(), "Finished crawl");
 
-    Ok(())
+    Ok(report)
 }
 
+#[tracing::instrument(name = "letter", skip_all, fields(url = %brief.link_to_html, record_id = %brief.id))]
 async fn bfarm_crawl_detailed_entry(brief: &mut Brief, client: reqwest::Client) -> Result<(), reqwest::Error> {
     let request = client.get(&brief.link_to_html).build()?;
-    let response = client.execute(request).await?;
+    let response = metrics::observe_upstream(Source::Bfarm, client.execute(request).await)?;
 
     let fragment = Html::parse_fragment(&response.text().await?);
 
@@ -249,88 +370,129 @@ async fn bfarm_crawl_detailed_entry(brief: &mut Brief, client: reqwest::Client)
     }
 }
 
-pub async fn crawl_pei(storage: Arc<TempStorage>) -> Result<(), reqwest::Error>{
+#[tracing::instrument(name = "crawl", skip_all, fields(source = "pei"))]
+pub async fn crawl_pei(storage: Arc<TempStorage>) -> Result<CrawlReport, reqwest::Error>{
     let client = storage.storage.read().await.reqwest_client.clone();
+    let pei_base_url = storage.config.pei_base_url.trim_end_matches('/');
+    let mut report = CrawlReport::default();
 
     let mut page = 1;
     let mut brief_links: Vec<String> = Vec::new();
 
-    loop{
-        let request = client.get(format!("https://www.pei.de/SiteGlobals/Forms/Suche/Sicherheitsinformationsuche_Formular.html?input_=170452&gtp=213258_list%253D{}&resourceId=211336&submit.x=22&submit.y=14&templateQueryString=&sortOrder=score+desc&pageLocale=de", page)).build()?;
-        let response = client.execute(request).await?;
-
-        let fragment = Html::parse_fragment(&response.text().await?);
+    // Whether the listing ended on an empty result page, only then unlisted letters are removed
+    let complete = loop{
+        let url = format!("{}/SiteGlobals/Forms/Suche/Sicherheitsinformationsuche_Formular.html?input_=170452&gtp=213258_list%253D{}&resourceId=211336&submit.x=22&submit.y=14&templateQueryString=&sortOrder=score+desc&pageLocale=de", pei_base_url, page);
+        let span = tracing::info_span!("page", page, %url);
+        let request = client.get(&url).build()?;
+        let response = metrics::observe_upstream(Source::Pei, client.execute(request).instrument(span.clone()).await)
+            .and_then(|response| response.error_for_status())?;
+        let html = response.text().instrument(span.clone()).await?;
+        let _entered = span.enter();
+        tracing::debug!("Parsing page");
+
+        let fragment = Html::parse_fragment(&html);
         let selector = Selector::parse(".searchresult > .teaser a").unwrap();
 
         let searchresults = fragment.select(&selector).collect::<Vec<ElementRef>>();
-        if searchresults.len() == 0 {
-            break;
+        if searchresults.is_empty() {
+            // Past the last entries the result list is still there, just empty
+            break fragment.select(&Selector::parse(".searchresult").unwrap()).next().is_some();
         }
         
         for link in searchresults {
             let title = link.text().collect::<String>().trim().to_lowercase();
-            if let Some(link_href) = link.attr("href"){
-                if title.contains("rote-hand-brief") || title.contains("rote-hand brief") || title.contains("rote hand brief") || title.contains("informationsbrief"){
-                    brief_links.push(format!("https://www.pei.de/{}", link_href));
-                }
+            if let Some(link_href) = link.attr("href") && (title.contains("rote-hand-brief") || title.contains("rote-hand brief") || title.contains("rote hand brief") || title.contains("informationsbrief")){
+                brief_links.push(format!("{}/{}", pei_base_url, link_href));
             }
         }
 
-        page = page+1;
-    }
+        page += 1;
+    };
 
-    println!("Found {} PEI letters. Crawling details...", brief_links.len());
-        
-    let mut letter_to_crawl = Vec::new();
-    for brief_link in brief_links {
-        if !storage.storage.read().await.briefe.contains_key(&brief_link){
-            letter_to_crawl.push(brief_link);
+    tracing::info!(letters = brief_links.len(), "Found letters");
+    report.parsed = brief_links.len();
+
+    // The search results only have the title, so besides the new letters a few known ones are crawled again in turns
+    // to notice changes
+    let listed: HashSet<String> = brief_links.iter().cloned().collect();
+    let mut letter_to_crawl = {
+        let mut handle = storage.storage.write().await;
+        let (mut known, mut new): (Vec<String>, Vec<String>) = brief_links.into_iter().partition(|link| handle.briefe.contains_key(link));
+        let recheck = storage.config.pei_recheck_letters.min(known.len());
+        if recheck > 0 {
+            known.sort();
+            let start = handle.pei_recheck_offset % known.len();
+            new.extend(known.into_iter().cycle().skip(start).take(recheck));
+            handle.pei_recheck_offset = start + recheck;
         }
-    }
-    
+        new
+    };
+    tracing::info!(letters = letter_to_crawl.len(), "Crawling letter pages");
+
     let mut future_res = Vec::new();
     
     while !letter_to_crawl.is_empty() {
-        println!("Processing next chunk of PEI letters to crawl.");
         let chunk_size = letter_to_crawl.len().min(MAX_CONCURRENT_REQUESTS as usize);
-        let mut next_links: Vec<String> = if letter_to_crawl.len() >= MAX_CONCURRENT_REQUESTS as usize {
+        let next_links: Vec<String> = if letter_to_crawl.len() >= MAX_CONCURRENT_REQUESTS as usize {
             letter_to_crawl.drain(..chunk_size).collect()
         }else{
-            letter_to_crawl.drain(..).collect()
+            std::mem::take(&mut letter_to_crawl)
         };
 
-        let futures = next_links.iter_mut().map(|link| pei_crawl_detailed_entry(client.clone(), link)).collect::<Vec<_>>();
-        future_res.append(&mut join_all(futures).await);
-        println!("Processecd chunks. Processed {} entries already.", future_res.len());
+        let futures = next_links.iter().map(|link| pei_crawl_detailed_entry(client.clone(), pei_base_url, link)).collect::<Vec<_>>();
+        let results = join_all(futures).await;
+        future_res.extend(next_links.into_iter().zip(results));
+        tracing::debug!(finished = future_res.len(), "Crawled next chunk");
     }
     
+    let now = Utc::now();
     let mut handle = storage.storage.write().await;
-    for future_result in future_res {
+    let mut events = Vec::new();
+    for (link, future_result) in future_res {
         match future_result {
-            Ok(val) => {
-                if let Some(val) = val{
-                    handle.briefe.insert(val.link_to_html.clone(), val);
+            Ok(Ok(val)) => {
+                if handle.briefe.get(&val.link_to_html).is_none_or(|known| known.fingerprint() != val.fingerprint()){
+                    events.push(store(&mut handle, val, now));
                 }
             }
+            Ok(Err(reason)) => {
+                tracing::warn!(url = %link, record_id = %letter_id(&link), reason, "Skipping letter");
+                report.parsed -= 1;
+                report.skip(reason);
+            }
             Err(e) => {
-                eprintln!("Failed to crawl pei letter: {}", e);
+                tracing::warn!(url = %link, record_id = %letter_id(&link), error = %e, "Failed to crawl letter");
+                report.parsed -= 1;
+                report.skip("letter page could not be fetched");
             }
         }
     }
+    if complete {
+        events.extend(remove_unlisted(&mut handle, LetterSource::PEI, &listed, now));
+    } else {
+        tracing::warn!(page, "Listing ended on an unexpected page, keeping the unlisted letters");
+    }
+    changes::log(&mut handle, events);
+    handle.briefe_refreshed_at = Some(now);
+    handle.update_derived_data();
 
-    println!("Finished crawl for PEI");
-    Ok(())
+    tracing::info!(parsed = report.parsed, skipped = report.skipped.values().sum::<usize>(), "Finished crawl");
+    Ok(report)
 }
 
-async fn pei_crawl_detailed_entry(client: reqwest::Client, url: &str) -> Result<Option<Brief>, reqwest::Error> {
+/// Why a PEI letter page was left out, counted with [`CrawlReport::skip`].
+type SkipReason = &'static str;
+
+#[tracing::instrument(name = "letter", skip(client, base_url), fields(record_id = %letter_id(url)))]
+async fn pei_crawl_detailed_entry(client: reqwest::Client, base_url: &str, url: &str) -> Result<Result<Brief, SkipReason>, reqwest::Error> {
     let request = client.get(url).build()?;
-    let response = client.execute(request).await?;
+    let response = metrics::observe_upstream(Source::Pei, client.execute(request).await)?;
 
     let fragment = Html::parse_fragment(&response.text().await?);
 
     let title_selector = Selector::parse(".content > h1").unwrap();
     let title = match fragment.select(&title_selector).next(){
-        None => return Ok(None),
+        None => return Ok(Err("letter page without title")),
         Some(title) => {
             title.text().collect::<String>().trim().to_string()
         }
@@ -361,15 +523,15 @@ async fn pei_crawl_detailed_entry(client: reqwest::Client, url: &str) -> Result<
         }
     };
     let download_a_tag = match fragment.select(&Selector::parse(".content a").unwrap()).next(){
-        None => return Ok(None),
+        None => return Ok(Err("letter page without PDF link")),
         Some(a_tag) => {
             a_tag
         }
     };
 
     let pdf_link = match download_a_tag.value().attr("href"){
-        None => return Ok(None),
-        Some(href) => format!("https://www.pei.de{}", href),
+        None => return Ok(Err("letter page without PDF link")),
+        Some(href) => format!("{}{}", base_url, href),
     };
 
     let download_link_text = download_a_tag.text().collect::<String>();
@@ -379,25 +541,25 @@ async fn pei_crawl_detailed_entry(client: reqwest::Client, url: &str) -> Result<
         match NaiveDate::parse_from_str(&caps[1], "%d.%m.%Y"){
             Ok(date) => date,
             Err(e) => {
-                eprintln!("Couldn't parse letter date: {}", e);
-                return Ok(None);
+                tracing::warn!(date = &caps[1], error = %e, "Couldn't parse letter date");
+                return Ok(Err("invalid date"));
             }
         }
     }else{
         // Second try to get date via updating date
         match fragment.select(&Selector::parse(".c-date__created > p").unwrap()).next(){
-            None => return Ok(None),
+            None => return Ok(Err("letter page without date")),
             Some(date_str) => {
                 let date_str = date_str.text().collect::<String>().trim().to_string();
                 match date_str.split("Aktualisiert:").last(){
-                    None => return Ok(None),
+                    None => return Ok(Err("letter page without date")),
                     Some(date_str) => {
-                        println!("Warning: using create date since no publishing date was found.");
+                        tracing::warn!("Using the update date since no publishing date was found");
                         match NaiveDate::parse_from_str(date_str, "%d.%m.%Y"){
                             Ok(date) => date,
                             Err(e) => {
-                                eprintln!("Couldn't parse letter date: {}", e);
-                                return Ok(None);
+                                tracing::warn!(date = date_str, error = %e, "Couldn't parse letter date");
+                                return Ok(Err("invalid date"));
                             }
                         }
                     }
@@ -406,15 +568,149 @@ async fn pei_crawl_detailed_entry(client: reqwest::Client, url: &str) -> Result<
         }
     };
 
-    Ok(Some(Brief{
+    Ok(Ok(Brief{
+        id: letter_id(url),
         letter_type,
         source: LetterSource::PEI,
         date,
         title,
         wirkstoffe: None,
+        wirkstoffe_normalized: Vec::new(),
         link_to_html: url.to_string(),
         link_to_pdf: pdf_link,
         short_description: description_short,
         long_description: None,
+        full_text: None,
+        related_lieferengpaesse: Vec::new(),
     }))
-}
\ No newline at end of file
+}
+
+enum FullTextError{
+    /// The PDF could not be downloaded, retried on the next refresh.
+    Download(reqwest::Error),
+    /// The PDF could not be read, not retried until restart.
+    Extract(String),
+}
+
+impl Display for FullTextError{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result{
+        match self{
+            FullTextError::Download(e) => write!(f, "download failed: {}", e),
+            FullTextError::Extract(e) => write!(f, "extraction failed: {}", e),
+        }
+    }
+}
+
+/// Joins the extracted lines, dropping the whitespace and empty lines the PDF layout leaves behind.
+fn normalize_text(text: &str) -> String{
+    let mut normalized = String::new();
+    let mut blank_lines = 0;
+    for line in text.lines(){
+        let line = line.split_whitespace().collect::<Vec<_>>().join(" ");
+        if line.is_empty(){
+            blank_lines += 1;
+            continue;
+        }
+        if !normalized.is_empty(){
+            normalized += if blank_lines > 0 { "\n\n" } else { "\n" };
+        }
+        normalized += &line;
+        blank_lines = 0;
+    }
+    normalized
+}
+
+#[tracing::instrument(name = "full_text", skip_all, fields(source = ?source, url = link_to_pdf))]
+async fn fetch_full_text(client: reqwest::Client, source: Source, link_to_pdf: &str) -> Result<String, FullTextError>{
+    let response = metrics::observe_upstream(source, client.get(link_to_pdf).send().await)
+        .and_then(|response| response.error_for_status())
+        .map_err(FullTextError::Download)?;
+    let pdf = response.bytes().await.map_err(FullTextError::Download)?;
+
+    // pdf-extract panics on some malformed files, the panic ends up in the JoinError
+    match tokio::task::spawn_blocking(move || pdf_extract::extract_text_from_mem(&pdf)).await{
+        Ok(Ok(text)) => Ok(normalize_text(&text)),
+        Ok(Err(e)) => Err(FullTextError::Extract(e.to_string())),
+        Err(e) => Err(FullTextError::Extract(e.to_string())),
+    }
+}
+
+/// Downloads the PDFs of all letters without full text and extracts their text. Returns the number of letters updated.
+#[tracing::instrument(skip_all)]
+pub async fn extract_full_texts(storage: Arc<TempStorage>) -> usize{
+    let (client, mut pending) = {
+        let handle = storage.storage.read().await;
+        let pending: Vec<(String, String, Source)> = handle.briefe.values()
+            .filter(|brief| brief.full_text.is_none() && !handle.full_text_failed.contains(&brief.link_to_html))
+            .map(|brief| (brief.link_to_html.clone(), brief.link_to_pdf.clone(), Source::from(&brief.source)))
+            .collect();
+        (handle.reqwest_client.clone(), pending)
+    };
+
+    tracing::info!(letters = pending.len(), "Extracting full texts");
+
+    let mut extracted = 0;
+    let mut failed = 0;
+    while !pending.is_empty(){
+        let chunk: Vec<(String, String, Source)> = pending.drain(..pending.len().min(MAX_CONCURRENT_REQUESTS as usize)).collect();
+        let futures = chunk.iter().map(|(_, link_to_pdf, source)| fetch_full_text(client.clone(), *source, link_to_pdf)).collect::<Vec<_>>();
+        let results = join_all(futures).await;
+
+        let mut handle = storage.storage.write().await;
+        for ((link_to_html, link_to_pdf, _), result) in chunk.into_iter().zip(results){
+            match result{
+                Ok(text) => {
+                    if let Some(brief) = handle.briefe.get_mut(&link_to_html){
+                        brief.full_text = Some(text);
+                        extracted += 1;
+                    }
+                }
+                Err(e) => {
+                    tracing::warn!(url = %link_to_pdf, error = %e, "Failed to get full text");
+                    if let FullTextError::Extract(_) = e && handle.full_text_failed.insert(link_to_html){
+                        failed += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    // The texts are stored per chunk, the search index and the data versions are updated once at the end
+    if extracted > 0 || failed > 0{
+        storage.storage.write().await.update_derived_data();
+    }
+
+    extracted
+}
+
+/// Fills the Wirkstoffe of PEI letters from the dictionary. Title and abstract are searched first, the text of the PDF
+/// only if they name none. Returns the number of letters changed.
+pub async fn detect_pei_wirkstoffe(storage: Arc<TempStorage>) -> usize{
+    let detected: Vec<(String, Option<Vec<String>>)> = {
+        let handle = storage.storage.read().await;
+        handle.briefe.values()
+            .filter(|brief| brief.source == LetterSource::PEI)
+            .filter_map(|brief| {
+                let heading = format!("{}\n{}", brief.title, brief.short_description.as_deref().unwrap_or_default());
+                let mut found = storage.wirkstoff_dictionary.find(&heading);
+                if found.is_empty() && let Some(full_text) = &brief.full_text{
+                    found = storage.wirkstoff_dictionary.find(full_text);
+                }
+                let wirkstoffe = if found.is_empty() { None } else { Some(found) };
+                (brief.wirkstoffe != wirkstoffe).then(|| (brief.link_to_html.clone(), wirkstoffe))
+            })
+            .collect()
+    };
+
+    let mut handle = storage.storage.write().await;
+    for (link_to_html, wirkstoffe) in &detected{
+        if let Some(brief) = handle.briefe.get_mut(link_to_html){
+            brief.wirkstoffe = wirkstoffe.clone();
+        }
+    }
+    if !detected.is_empty(){
+        handle.update_derived_data();
+    }
+
+    detected.len()
+}
@@ -0,0 +1,342 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use rust_stemmers::{Algorithm, Stemmer};
+use serde::Serialize;
+use crate::changes::Dataset;
+use crate::lieferengpaesse::{Lieferengpass, LieferengpassStatus};
+use crate::rote_hand_briefe::Brief;
+use crate::InnerStorage;
+
+/// BM25 term frequency saturation.
+const K1: f64 = 1.2;
+/// BM25 document length normalization.
+const B: f64 = 0.75;
+
+const TITLE_WEIGHT: f64 = 3.0;
+const WIRKSTOFF_WEIGHT: f64 = 2.0;
+const TEXT_WEIGHT: f64 = 1.0;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct DocumentKey{
+    dataset: Dataset,
+    id: String,
+}
+
+struct IndexedDocument{
+    /// Hash of the indexed fields, the document is only re-indexed if it changes.
+    fingerprint: u64,
+    /// Sum of the weighted term frequencies.
+    length: f64,
+    terms: Vec<String>,
+}
+
+/// Inverted index over letters and Lieferengpässe, ranked with BM25. Fields are weighted by
+/// counting each of their terms with the field's weight.
+#[derive(Default)]
+pub struct SearchIndex{
+    documents: HashMap<DocumentKey, IndexedDocument>,
+    /// Weighted term frequency per document, by term.
+    postings: HashMap<String, HashMap<DocumentKey, f64>>,
+    /// Indexed terms containing umlaut transcriptions, by their folded form, see [`fold_transcriptions`].
+    transcriptions: HashMap<String, HashSet<String>>,
+    total_length: f64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "dataset", content = "record", rename_all = "snake_case")]
+// Only built for the hits of a page, boxing wouldn't save anything
+#[allow(clippy::large_enum_variant)]
+pub enum SearchRecord{
+    Lieferengpaesse(Lieferengpass),
+    Briefe(Brief),
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct SearchHit{
+    pub id: String,
+    pub score: f64,
+    #[serde(flatten)]
+    pub record: SearchRecord,
+}
+
+/// Replaces the transcriptions `ae`, `oe` and `ue` of a stemmed term with the vowel, returns `None` if it has none.
+/// The stemmer already folds umlauts, so "lieferengpaess" becomes "lieferengpass" like the stem of "Lieferengpässe".
+///
+/// Transcriptions after another vowel or `q` are kept, as in "Bauer" or "Quelle". Words like "Poet" or "Aerosol"
+/// can't be told apart from transcriptions, so this is only a fallback for terms that aren't indexed as they are.
+fn fold_transcriptions(term: &str) -> Option<String>{
+    let mut folded: Vec<char> = Vec::with_capacity(term.len());
+    let mut changed = false;
+    for c in term.chars(){
+        let transcribed = c == 'e' && match folded.as_slice(){
+            [vowel] => matches!(vowel, 'a' | 'o' | 'u'),
+            [.., before, vowel] => matches!(vowel, 'a' | 'o' | 'u') && !matches!(before, 'a' | 'e' | 'i' | 'o' | 'u' | 'y' | 'q'),
+            _ => false,
+        };
+        if transcribed{
+            changed = true;
+        }else{
+            folded.push(c);
+        }
+    }
+    changed.then(|| folded.into_iter().collect())
+}
+
+/// Splits text into lowercase, stemmed terms. The German stemmer also folds umlauts and `ß`.
+fn tokenize(text: &str) -> Vec<String>{
+    let stemmer = Stemmer::create(Algorithm::German);
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.chars().count() > 1)
+        .map(|word| stemmer.stem(&word.to_lowercase()).into_owned())
+        .collect()
+}
+
+fn fingerprint(fields: &[(&str, f64)]) -> u64{
+    let mut hasher = DefaultHasher::new();
+    for (text, weight) in fields{
+        text.hash(&mut hasher);
+        weight.to_bits().hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+fn brief_fields(brief: &Brief) -> Vec<(&str, f64)>{
+    let mut fields = vec![(brief.title.as_str(), TITLE_WEIGHT)];
+    fields.extend(brief.wirkstoffe.iter().flatten().map(|wirkstoff| (wirkstoff.as_str(), WIRKSTOFF_WEIGHT)));
+    fields.extend([&brief.short_description, &brief.long_description, &brief.full_text].into_iter().flatten().map(|text| (text.as_str(), TEXT_WEIGHT)));
+    fields
+}
+
+fn lieferengpass_fields(entry: &Lieferengpass) -> Vec<(&str, f64)>{
+    let mut fields = vec![
+        (entry.arzneimittelbezeichnung.as_str(), TITLE_WEIGHT),
+        (entry.wirkstoffe.as_str(), WIRKSTOFF_WEIGHT),
+        (entry.grund.as_str(), TEXT_WEIGHT),
+    ];
+    fields.extend(entry.alternativpraeparat.iter().map(|text| (text.as_str(), TEXT_WEIGHT)));
+    fields
+}
+
+impl SearchIndex{
+    fn remove(&mut self, key: &DocumentKey){
+        let Some(document) = self.documents.remove(key) else { return };
+        self.total_length -= document.length;
+        for term in document.terms{
+            if let Some(posting) = self.postings.get_mut(&term){
+                posting.remove(key);
+                if posting.is_empty(){
+                    self.postings.remove(&term);
+                    if let Some(folded) = fold_transcriptions(&term) && let Some(terms) = self.transcriptions.get_mut(&folded){
+                        terms.remove(&term);
+                        if terms.is_empty(){
+                            self.transcriptions.remove(&folded);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn insert(&mut self, key: DocumentKey, fingerprint: u64, fields: &[(&str, f64)]){
+        let mut frequencies: HashMap<String, f64> = HashMap::new();
+        for (text, weight) in fields{
+            for term in tokenize(text){
+                *frequencies.entry(term).or_default() += weight;
+            }
+        }
+
+        let length = frequencies.values().sum();
+        self.total_length += length;
+        for (term, frequency) in &frequencies{
+            if !self.postings.contains_key(term) && let Some(folded) = fold_transcriptions(term){
+                self.transcriptions.entry(folded).or_default().insert(term.clone());
+            }
+            self.postings.entry(term.clone()).or_default().insert(key.clone(), *frequency);
+        }
+        self.documents.insert(key, IndexedDocument{ fingerprint, length, terms: frequencies.into_keys().collect() });
+    }
+
+    /// Brings the documents of a dataset up to date. Only new and changed documents are tokenized.
+    fn sync<'a>(&mut self, dataset: Dataset, documents: impl IntoIterator<Item = (&'a str, Vec<(&'a str, f64)>)>){
+        let mut seen = HashSet::new();
+        for (id, fields) in documents{
+            let key = DocumentKey{ dataset, id: id.to_string() };
+            let fingerprint = fingerprint(&fields);
+            if self.documents.get(&key).is_some_and(|document| document.fingerprint == fingerprint){
+                seen.insert(key);
+                continue;
+            }
+            self.remove(&key);
+            self.insert(key.clone(), fingerprint, &fields);
+            seen.insert(key);
+        }
+
+        let removed: Vec<DocumentKey> = self.documents.keys().filter(|key| key.dataset == dataset && !seen.contains(*key)).cloned().collect();
+        for key in removed{
+            self.remove(&key);
+        }
+    }
+
+    /// Indexed terms a query term matches. Umlaut transcriptions are only considered if the term isn't indexed as it
+    /// is, so "Lieferengpaesse" and "Lieferengpässe" find each other while "Poet" doesn't find "Pot".
+    fn matching_terms<'a>(&'a self, term: &'a str) -> Vec<&'a str>{
+        if self.postings.contains_key(term){
+            return vec![term];
+        }
+        let mut terms: Vec<&str> = self.transcriptions.get(term).into_iter().flatten().map(String::as_str).collect();
+        if let Some(folded) = fold_transcriptions(term) && let Some((indexed, _)) = self.postings.get_key_value(&folded){
+            terms.push(indexed);
+        }
+        terms
+    }
+
+    /// Documents containing any of the query terms, best match first.
+    fn search(&self, query: &str, dataset: Option<Dataset>) -> Vec<(&DocumentKey, f64)>{
+        let count = self.documents.len() as f64;
+        let average_length = if self.documents.is_empty() { 1.0 } else { self.total_length / count };
+
+        let query_terms: HashSet<String> = tokenize(query).into_iter().collect();
+        let terms: HashSet<&str> = query_terms.iter().flat_map(|term| self.matching_terms(term)).collect();
+        let mut scores: HashMap<&DocumentKey, f64> = HashMap::new();
+        for term in terms{
+            let Some(posting) = self.postings.get(term) else { continue };
+            let matching = posting.len() as f64;
+            let idf = (1.0 + (count - matching + 0.5) / (matching + 0.5)).ln();
+
+            for (key, frequency) in posting{
+                if dataset.is_some_and(|dataset| key.dataset != dataset){
+                    continue;
+                }
+                let length = self.documents.get(key).map_or(average_length, |document| document.length);
+                let score = idf * frequency * (K1 + 1.0) / (frequency + K1 * (1.0 - B + B * length / average_length));
+                *scores.entry(key).or_default() += score;
+            }
+        }
+
+        let mut hits: Vec<(&DocumentKey, f64)> = scores.into_iter().collect();
+        hits.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.id.cmp(&b.0.id)));
+        hits
+    }
+}
+
+/// Whether the query contains anything that can be searched for.
+pub fn is_searchable(query: &str) -> bool{
+    !tokenize(query).is_empty()
+}
+
+/// Updates the index after a refresh. Vanished Lieferengpässe are left out like in the list endpoint.
+pub fn sync(storage: &mut InnerStorage){
+    let briefe = storage.briefe.values().map(|brief| (brief.id.as_str(), brief_fields(brief)));
+    storage.search_index.sync(Dataset::Briefe, briefe);
+
+    let lieferengpaesse = storage.lieferengpaesse.iter()
+        .filter(|entry| entry.status != LieferengpassStatus::Vanished)
+        .map(|entry| (entry.bearbeitungsnummer.as_str(), lieferengpass_fields(entry)));
+    storage.search_index.sync(Dataset::Lieferengpaesse, lieferengpaesse);
+}
+
+/// Ranked matches for the query, as keys and scores. Resolve them with [`resolve`].
+pub fn search(storage: &InnerStorage, query: &str, dataset: Option<Dataset>) -> Vec<(Dataset, String, f64)>{
+    storage.search_index.search(query, dataset).into_iter()
+        .map(|(key, score)| (key.dataset, key.id.clone(), score))
+        .collect()
+}
+
+/// Looks up the records of the hits. The PDF text of letters is left out.
+pub fn resolve(storage: &InnerStorage, hits: Vec<(Dataset, String, f64)>) -> Vec<SearchHit>{
+    let lieferengpaesse_by_id: HashMap<&str, &Lieferengpass> = storage.lieferengpaesse.iter().map(|entry| (entry.bearbeitungsnummer.as_str(), entry)).collect();
+    let briefe_by_id: HashMap<&str, &Brief> = storage.briefe.values().map(|brief| (brief.id.as_str(), brief)).collect();
+
+    hits.into_iter().filter_map(|(dataset, id, score)| {
+        let record = match dataset{
+            Dataset::Lieferengpaesse => SearchRecord::Lieferengpaesse((*lieferengpaesse_by_id.get(id.as_str())?).clone()),
+            Dataset::Briefe => {
+                let mut brief = (*briefe_by_id.get(id.as_str())?).clone();
+                brief.full_text = None;
+                SearchRecord::Briefe(brief)
+            }
+        };
+        Some(SearchHit{ id, score, record })
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn index(documents: &[(&str, &[(&str, f64)])]) -> SearchIndex{
+        let mut index = SearchIndex::default();
+        index.sync(Dataset::Briefe, documents.iter().map(|(id, fields)| (*id, fields.to_vec())));
+        index
+    }
+
+    fn ids(index: &SearchIndex, query: &str) -> Vec<String>{
+        index.search(query, None).into_iter().map(|(key, _)| key.id.clone()).collect()
+    }
+
+    #[test]
+    fn folds_transcriptions_after_consonants_only(){
+        for (term, folded) in [
+            ("lieferengpaess", Some("lieferengpass")),
+            ("muedig", Some("mudig")),
+            ("uebel", Some("ubel")),
+            ("bau", None),
+            ("quell", None),
+            ("neu", None),
+            ("amoxicillin", None),
+        ]{
+            assert_eq!(fold_transcriptions(term).as_deref(), folded, "{term}");
+        }
+    }
+
+    #[test]
+    fn keeps_words_that_look_like_transcriptions(){
+        assert_eq!(tokenize("Aerosol Poet"), ["aerosol", "poet"]);
+    }
+
+    #[test]
+    fn matches_umlauts_and_transcriptions_both_ways(){
+        let index = index(&[
+            ("umlaut", &[("Lieferengpässe bei Antibiotika", TEXT_WEIGHT)]),
+            ("transcribed", &[("Muedigkeit nach der Impfung", TEXT_WEIGHT)]),
+        ]);
+
+        for (query, expected) in [
+            ("Lieferengpaesse", "umlaut"),
+            ("Lieferengpässe", "umlaut"),
+            ("Lieferengpass", "umlaut"),
+            ("Müdigkeit", "transcribed"),
+            ("Muedigkeit", "transcribed"),
+        ]{
+            assert_eq!(ids(&index, query), [expected], "{query}");
+        }
+    }
+
+    #[test]
+    fn prefers_exact_terms_over_folded_ones(){
+        let index = index(&[
+            ("poet", &[("Poet", TEXT_WEIGHT)]),
+            ("pot", &[("Pot", TEXT_WEIGHT)]),
+            ("aerosol", &[("Aerosol", TEXT_WEIGHT)]),
+        ]);
+
+        assert_eq!(ids(&index, "Poet"), ["poet"]);
+        assert_eq!(ids(&index, "Pot"), ["pot"]);
+        assert_eq!(ids(&index, "Aerosol"), ["aerosol"]);
+    }
+
+    #[test]
+    fn ranks_by_field_weight_length_and_rarity(){
+        let index = index(&[
+            ("title", &[("Amoxicillin", TITLE_WEIGHT), ("Trockensaft für Kinder", TEXT_WEIGHT)]),
+            ("text", &[("Rote-Hand-Brief", TITLE_WEIGHT), ("Amoxicillin Trockensaft für Kinder", TEXT_WEIGHT)]),
+            ("long_text", &[("Rote-Hand-Brief", TITLE_WEIGHT), ("Amoxicillin Trockensaft für Kinder und Erwachsene in allen Stärken", TEXT_WEIGHT)]),
+            ("unrelated", &[("Metamizol", TITLE_WEIGHT)]),
+        ]);
+
+        assert_eq!(ids(&index, "Amoxicillin"), ["title", "text", "long_text"], "title before text, shorter before longer");
+        // "Erwachsene" only appears once and outweighs the common terms
+        assert_eq!(ids(&index, "Trockensaft Erwachsene")[0], "long_text");
+    }
+}
@@ -0,0 +1,259 @@
+use std::collections::HashMap;
+use chrono::{DateTime, Datelike, Duration, Months, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use crate::filter::LieferengpassFilter;
+use crate::lieferengpaesse::{Lieferengpass, LieferengpassStatus};
+
+/// Upper bound for the number of periods of a timeline.
+pub const MAX_PERIODS: usize = 1000;
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum StatsInterval{
+    /// Weeks starting on Monday.
+    Week,
+    #[default]
+    Month,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Count{
+    pub value: String,
+    pub count: usize,
+}
+
+/// Aggregates over a set of Lieferengpässe.
+#[derive(Serialize, Debug, Clone)]
+pub struct Breakdown{
+    pub lieferengpaesse: usize,
+    pub kkh_relevant: usize,
+    /// Mean of `ende - beginn` in days.
+    pub average_duration_days: Option<f64>,
+    pub by_art_des_grundes: Vec<Count>,
+    pub by_klassifikation: Vec<Count>,
+    pub by_zulassungsinhaber: Vec<Count>,
+    pub by_darreichungsform: Vec<Count>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Period{
+    pub start: NaiveDate,
+    pub end: NaiveDate,
+    /// Lieferengpässe that began during the period.
+    pub started: usize,
+    /// Lieferengpässe that ended or vanished during the period.
+    pub ended: usize,
+    /// Aggregates over all Lieferengpässe active at some point of the period.
+    #[serde(flatten)]
+    pub active: Breakdown,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Summary{
+    pub data_timestamp: Option<DateTime<Utc>>,
+    #[serde(flatten)]
+    pub active: Breakdown,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Timeline{
+    pub interval: StatsInterval,
+    pub data_timestamp: Option<DateTime<Utc>>,
+    pub periods: Vec<Period>,
+}
+
+/// Enum values are counted by their serde name, like in the JSON output.
+fn serde_name<T: Serialize>(value: &T) -> String{
+    match serde_json::to_value(value){
+        Ok(serde_json::Value::String(text)) => text,
+        Ok(other) => other.to_string(),
+        Err(_) => String::new(),
+    }
+}
+
+/// Counts per value, most frequent first. `top` limits the number of values returned.
+fn counts(values: impl Iterator<Item = String>, top: Option<usize>) -> Vec<Count>{
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for value in values{
+        *counts.entry(value).or_default() += 1;
+    }
+
+    let mut counts: Vec<Count> = counts.into_iter().map(|(value, count)| Count{ value, count }).collect();
+    counts.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.value.cmp(&b.value)));
+    counts.truncate(top.unwrap_or(usize::MAX));
+    counts
+}
+
+/// `top` only applies to Zulassungsinhaber and Darreichungsform, the other breakdowns have few values.
+pub fn breakdown(entries: &[&Lieferengpass], top: Option<usize>) -> Breakdown{
+    let durations: Vec<i64> = entries.iter().map(|entry| (entry.ende - entry.beginn).num_days()).collect();
+
+    Breakdown{
+        lieferengpaesse: entries.len(),
+        kkh_relevant: entries.iter().filter(|entry| entry.kkh_relevant).count(),
+        average_duration_days: (!durations.is_empty()).then(|| durations.iter().sum::<i64>() as f64 / durations.len() as f64),
+        by_art_des_grundes: counts(entries.iter().map(|entry| serde_name(&entry.art_des_grundes)), None),
+        by_klassifikation: counts(entries.iter().map(|entry| serde_name(&entry.klassifikation)), None),
+        by_zulassungsinhaber: counts(entries.iter().map(|entry| entry.zulassungsinhaber.trim().to_string()), top),
+        by_darreichungsform: counts(entries.iter().map(|entry| entry.darreichungsform.trim().to_string()), top),
+    }
+}
+
+/// Aggregates over the currently active Lieferengpässe matching the filter.
+pub fn summary(entries: &[Lieferengpass], filter: &LieferengpassFilter, top: Option<usize>) -> Breakdown{
+    let active: Vec<&Lieferengpass> = entries.iter()
+        .filter(|entry| entry.status == LieferengpassStatus::Active && filter.matches_fields(entry))
+        .collect();
+    breakdown(&active, top)
+}
+
+/// First day of the period containing `date`.
+pub fn period_start(date: NaiveDate, interval: StatsInterval) -> NaiveDate{
+    match interval{
+        StatsInterval::Week => date - Duration::days(date.weekday().num_days_from_monday() as i64),
+        StatsInterval::Month => date.with_day(1).unwrap_or(date),
+    }
+}
+
+fn next_period(start: NaiveDate, interval: StatsInterval) -> NaiveDate{
+    match interval{
+        StatsInterval::Week => start + Duration::days(7),
+        StatsInterval::Month => start.checked_add_months(Months::new(1)).unwrap_or(NaiveDate::MAX),
+    }
+}
+
+/// Last day a shortage counted as active: `ende`, or the day it vanished from the CSV if that was earlier.
+fn effective_end(entry: &Lieferengpass) -> NaiveDate{
+    entry.vanished_at.map_or(entry.ende, |vanished_at| vanished_at.date_naive().min(entry.ende))
+}
+
+/// Periods covering `from` to `to`, or `None` if there would be more than [`MAX_PERIODS`].
+pub fn periods(from: NaiveDate, to: NaiveDate, interval: StatsInterval) -> Option<Vec<(NaiveDate, NaiveDate)>>{
+    let mut periods = Vec::new();
+    let mut start = period_start(from, interval);
+    while start <= to{
+        if periods.len() == MAX_PERIODS{
+            return None;
+        }
+        let next = next_period(start, interval);
+        periods.push((start, next - Duration::days(1)));
+        start = next;
+    }
+    Some(periods)
+}
+
+/// Aggregates per period. Withdrawn shortages (Löschmeldung) are left out unless asked for with `status`,
+/// vanished ones are counted until they vanished.
+pub fn timeline(entries: &[Lieferengpass], filter: &LieferengpassFilter, periods: &[(NaiveDate, NaiveDate)], top: Option<usize>) -> Vec<Period>{
+    let entries: Vec<&Lieferengpass> = entries.iter()
+        .filter(|entry| match filter.status{
+            Some(status) => entry.status == status,
+            None => entry.status != LieferengpassStatus::Deleted,
+        })
+        .filter(|entry| filter.matches_fields(entry))
+        .collect();
+
+    periods.iter().map(|(start, end)| {
+        let active: Vec<&Lieferengpass> = entries.iter().copied()
+            .filter(|entry| entry.beginn <= *end && effective_end(entry) >= *start)
+            .collect();
+
+        Period{
+            start: *start,
+            end: *end,
+            started: active.iter().filter(|entry| entry.beginn >= *start).count(),
+            ended: active.iter().filter(|entry| effective_end(entry) <= *end).count(),
+            active: breakdown(&active, top),
+        }
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    const HEADER: &str = "PZN;ENR;Bearbeitungsnummer;Referenzierte Erstmeldung;Datum der Erstmeldung;Meldungsart;Beginn;Ende;Datum der letzten Meldung;Art des Grundes;Arzneimittlbezeichnung;Atc Code;Wirkstoffe;Krankenhausrelevant;Zulassungsinhaber;Grund;Anm. zum Grund;Alternativpräparat;Info an Fachkreise;Darreichungsform;klassifikation";
+
+    fn date(text: &str) -> NaiveDate{
+        NaiveDate::parse_from_str(text, "%Y-%m-%d").unwrap()
+    }
+
+    /// A shortage from `beginn` to `ende`, both as `dd.mm.yyyy` like in the CSV.
+    fn lieferengpass(bearbeitungsnummer: &str, beginn: &str, ende: &str) -> Lieferengpass{
+        let row = format!("1234567;12345;{bearbeitungsnummer};N/A;01.12.2024;Erstmeldung;{beginn};{ende};01.12.2024;Produktionsproblem;Metamizol 500 mg Tabletten;N02BB02;Metamizol-Natrium;Nein;Pharma GmbH;Produktionsproblem;N/A;N/A;Nein;Tablette;versrel");
+        let csv = format!("{HEADER}\n{row}");
+        csv::ReaderBuilder::new().delimiter(b';').from_reader(csv.as_bytes()).deserialize().next().unwrap().unwrap()
+    }
+
+    fn periods_of(from: &str, to: &str, interval: StatsInterval) -> Vec<(NaiveDate, NaiveDate)>{
+        periods(date(from), date(to), interval).unwrap()
+    }
+
+    #[test]
+    fn buckets_weeks_across_the_year_boundary(){
+        for (day, monday) in [
+            ("2024-12-29", "2024-12-23"),
+            ("2024-12-30", "2024-12-30"),
+            ("2025-01-01", "2024-12-30"),
+            ("2025-01-05", "2024-12-30"),
+            ("2025-01-06", "2025-01-06"),
+            // ISO week 53 of 2020 ends in 2021
+            ("2021-01-03", "2020-12-28"),
+        ]{
+            assert_eq!(period_start(date(day), StatsInterval::Week), date(monday), "{day}");
+        }
+
+        assert_eq!(periods_of("2024-12-28", "2025-01-08", StatsInterval::Week), [
+            (date("2024-12-23"), date("2024-12-29")),
+            (date("2024-12-30"), date("2025-01-05")),
+            (date("2025-01-06"), date("2025-01-12")),
+        ]);
+    }
+
+    #[test]
+    fn buckets_months_across_the_year_boundary(){
+        assert_eq!(period_start(date("2025-01-31"), StatsInterval::Month), date("2025-01-01"));
+        assert_eq!(periods_of("2024-11-15", "2025-03-01", StatsInterval::Month), [
+            (date("2024-11-01"), date("2024-11-30")),
+            (date("2024-12-01"), date("2024-12-31")),
+            (date("2025-01-01"), date("2025-01-31")),
+            (date("2025-02-01"), date("2025-02-28")),
+            (date("2025-03-01"), date("2025-03-31")),
+        ]);
+        assert_eq!(periods_of("2024-02-10", "2024-02-10", StatsInterval::Month), [(date("2024-02-01"), date("2024-02-29"))]);
+    }
+
+    #[test]
+    fn limits_the_number_of_periods(){
+        let from = date("2000-01-03");
+        let last_day = from + Duration::days(7 * MAX_PERIODS as i64 - 1);
+        assert_eq!(periods(from, last_day, StatsInterval::Week).map(|periods| periods.len()), Some(MAX_PERIODS));
+        assert_eq!(periods(from, last_day + Duration::days(1), StatsInterval::Week), None);
+
+        let from = date("1950-01-01");
+        let last_day = date("2033-04-30");
+        assert_eq!(periods(from, last_day, StatsInterval::Month).map(|periods| periods.len()), Some(MAX_PERIODS));
+        assert_eq!(periods(from, last_day + Duration::days(1), StatsInterval::Month), None);
+    }
+
+    #[test]
+    fn counts_shortages_per_period_across_the_year_boundary(){
+        let mut vanished = lieferengpass("2024-0003", "01.11.2024", "31.12.2030");
+        vanished.vanished_at = Some(date("2024-12-31").and_hms_opt(12, 0, 0).unwrap().and_utc());
+        let entries = [
+            lieferengpass("2024-0001", "15.12.2024", "15.01.2025"),
+            lieferengpass("2025-0002", "02.01.2025", "31.12.2030"),
+            vanished,
+        ];
+
+        let periods = periods_of("2024-12-01", "2025-01-31", StatsInterval::Month);
+        let periods = timeline(&entries, &LieferengpassFilter::default(), &periods, None);
+        let counts: Vec<(usize, usize, usize)> = periods.iter().map(|period| (period.started, period.ended, period.active.lieferengpaesse)).collect();
+        assert_eq!(counts, [(1, 1, 2), (1, 1, 2)], "December: 0001 starts, 0003 vanishes. January: 0002 starts, 0001 ends");
+
+        let periods = periods_of("2024-12-30", "2025-01-12", StatsInterval::Week);
+        let periods = timeline(&entries, &LieferengpassFilter::default(), &periods, None);
+        let counts: Vec<(usize, usize, usize)> = periods.iter().map(|period| (period.started, period.ended, period.active.lieferengpaesse)).collect();
+        assert_eq!(counts, [(1, 1, 3), (0, 0, 2)]);
+    }
+}
@@ -0,0 +1,95 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use crate::rote_hand_briefe::LetterSource;
+use crate::{InnerStorage, TempStorage};
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Source{
+    Bfarm,
+    Pei,
+    /// The Lieferengpass CSV.
+    Pharmnet,
+}
+
+impl From<&LetterSource> for Source{
+    fn from(source: &LetterSource) -> Self{
+        match source{
+            LetterSource::BfArM => Source::Bfarm,
+            LetterSource::PEI => Source::Pei,
+        }
+    }
+}
+
+/// Outcome of a crawl that reached the source.
+#[derive(Debug, Clone, Default)]
+pub struct CrawlReport{
+    /// Records read from the source, including ones already known.
+    pub parsed: usize,
+    /// Number of records left out, by reason.
+    pub skipped: BTreeMap<String, usize>,
+}
+
+impl CrawlReport{
+    pub fn skip(&mut self, reason: impl Into<String>){
+        *self.skipped.entry(reason.into()).or_default() += 1;
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct SourceStatus{
+    pub last_attempt: Option<DateTime<Utc>>,
+    pub last_success: Option<DateTime<Utc>>,
+    /// Duration of the last attempt.
+    pub duration_ms: Option<u128>,
+    /// Records parsed by the last successful attempt.
+    pub parsed: usize,
+    /// Records skipped by the last successful attempt, in total and by reason.
+    pub skipped: usize,
+    pub skip_reasons: BTreeMap<String, usize>,
+    /// Kept after later successes, compare `last_error_at` with `last_success`.
+    pub last_error: Option<String>,
+    pub last_error_at: Option<DateTime<Utc>>,
+}
+
+/// The error with its causes, e.g. the DNS failure behind a failed request.
+fn describe(error: &dyn Error) -> String{
+    let mut description = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source{
+        description += &format!(": {}", cause);
+        source = cause.source();
+    }
+    description
+}
+
+/// Records the outcome of a crawl started at `attempted_at`.
+pub async fn record<E: Error>(storage: &TempStorage, source: Source, attempted_at: DateTime<Utc>, duration: Duration, result: &Result<CrawlReport, E>){
+    crate::metrics::observe_refresh(source, duration, result);
+    let mut handle = storage.storage.write().await;
+    let status = handle.source_status.entry(source).or_default();
+    status.last_attempt = Some(attempted_at);
+    status.duration_ms = Some(duration.as_millis());
+    match result{
+        Ok(report) => {
+            status.last_success = Some(attempted_at);
+            status.parsed = report.parsed;
+            status.skipped = report.skipped.values().sum();
+            status.skip_reasons = report.skipped.clone();
+        }
+        Err(e) => {
+            status.last_error = Some(describe(e));
+            status.last_error_at = Some(attempted_at);
+        }
+    }
+}
+
+/// Status of every source, also the ones not attempted yet.
+pub fn overview(storage: &InnerStorage) -> BTreeMap<Source, SourceStatus>{
+    [Source::Bfarm, Source::Pei, Source::Pharmnet].into_iter()
+        .map(|source| (source, storage.source_status.get(&source).cloned().unwrap_or_default()))
+        .collect()
+}
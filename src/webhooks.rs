@@ -0,0 +1,300 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::Url;
+use rocket::tokio;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+use crate::changes::{Change, ChangeSet};
+use crate::lieferengpaesse::Lieferengpass;
+use crate::rote_hand_briefe::{Brief, LetterSource};
+use crate::TempStorage;
+
+pub const SIGNATURE_HEADER: &str = "X-MediHelp-Signature";
+
+/// Criteria a record has to match to be delivered. Unset criteria match everything.
+///
+/// `atc` and `pzn` only apply to Lieferengpässe, `source` only to letters. Setting a criterion of one
+/// dataset excludes the other dataset, e.g. a webhook with a `pzn` list never receives letters.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct WebhookFilter{
+    /// Prefix of the ATC code.
+    #[serde(default)]
+    pub atc: Option<String>,
+    #[serde(default)]
+    pub pzn: Vec<usize>,
+    /// Case-insensitive substring of the Wirkstoffe.
+    #[serde(default)]
+    pub wirkstoff: Option<String>,
+    #[serde(default)]
+    pub source: Option<LetterSource>,
+}
+
+impl WebhookFilter{
+    pub fn matches_lieferengpass(&self, entry: &Lieferengpass) -> bool{
+        if self.source.is_some(){
+            return false;
+        }
+        if let Some(atc) = &self.atc && !entry.atc.to_uppercase().starts_with(&atc.trim().to_uppercase()){
+            return false;
+        }
+        if !self.pzn.is_empty() && !self.pzn.contains(&entry.pzn){
+            return false;
+        }
+        match &self.wirkstoff{
+            Some(wirkstoff) => entry.wirkstoffe.to_lowercase().contains(&wirkstoff.trim().to_lowercase()),
+            None => true,
+        }
+    }
+
+    /// Whether every letter matches, which is all that can be said about removed letters.
+    pub fn matches_all_briefe(&self) -> bool{
+        self.atc.is_none() && self.pzn.is_empty() && self.source.is_none() && self.wirkstoff.is_none()
+    }
+
+    pub fn matches_brief(&self, brief: &Brief) -> bool{
+        if self.atc.is_some() || !self.pzn.is_empty(){
+            return false;
+        }
+        if self.source.as_ref().is_some_and(|source| &brief.source != source){
+            return false;
+        }
+        match &self.wirkstoff{
+            Some(wirkstoff) => {
+                let wirkstoff = wirkstoff.trim().to_lowercase();
+                brief.wirkstoffe.as_ref().is_some_and(|wirkstoffe| wirkstoffe.iter().any(|entry| entry.to_lowercase().contains(&wirkstoff)))
+            }
+            None => true,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Webhook{
+    pub id: String,
+    pub url: String,
+    /// Key for the HMAC signature of the deliveries. Only returned on registration.
+    #[serde(skip_serializing)]
+    pub secret: String,
+    pub filter: WebhookFilter,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Webhook{
+    pub fn new(url: String, filter: WebhookFilter) -> Self{
+        Webhook{
+            id: random_token(8),
+            url,
+            secret: random_token(32),
+            filter,
+            created_at: Utc::now(),
+        }
+    }
+
+    /// Compares in constant time, so the secret can't be guessed byte by byte from the response times.
+    pub fn has_secret(&self, secret: &str) -> bool{
+        self.secret.as_bytes().ct_eq(secret.as_bytes()).into()
+    }
+}
+
+/// Whether the address is reachable on the public internet. Loopback, private, link-local and other special-purpose
+/// addresses are not.
+pub fn is_public(ip: IpAddr) -> bool{
+    match ip{
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped(){
+            Some(ip) => is_public_v4(ip),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool{
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_unspecified() || ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_broadcast()
+        || ip.is_documentation() || ip.is_multicast()
+        // "This network", carrier-grade NAT, IETF protocol assignments, benchmarking and reserved
+        || a == 0 || (a == 100 && (64..128).contains(&b)) || (a == 192 && b == 0 && c == 0)
+        || (a == 198 && (18..20).contains(&b)) || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool{
+    let documentation = ip.segments()[0] == 0x2001 && ip.segments()[1] == 0x0db8;
+    !(ip.is_unspecified() || ip.is_loopback() || ip.is_multicast() || ip.is_unique_local() || ip.is_unicast_link_local() || documentation)
+}
+
+/// Resolves the host of a webhook URL and fails if any of its addresses isn't public.
+pub async fn check_target(url: &Url) -> Result<(), String>{
+    let Some(host) = url.host_str() else {
+        return Err("url without host".to_string());
+    };
+    // IPv6 addresses are in brackets
+    if let Ok(ip) = host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>(){
+        return public_or_error(ip);
+    }
+    let addresses: Vec<SocketAddr> = tokio::net::lookup_host((host, url.port_or_known_default().unwrap_or(0))).await
+        .map_err(|e| format!("can't resolve {}: {}", host, e))?
+        .collect();
+    if addresses.is_empty(){
+        return Err(format!("can't resolve {}", host));
+    }
+    addresses.into_iter().try_for_each(|address| public_or_error(address.ip()))
+}
+
+fn public_or_error(ip: IpAddr) -> Result<(), String>{
+    if is_public(ip) { Ok(()) } else { Err(format!("{} is not a public address", ip)) }
+}
+
+/// Resolver of the delivery client. Checks the addresses again on every connection, as the DNS answer may have
+/// changed since the webhook was registered.
+struct PublicOnlyResolver;
+
+impl Resolve for PublicOnlyResolver{
+    fn resolve(&self, name: Name) -> Resolving{
+        Box::pin(async move {
+            let addresses: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0)).await?.collect();
+            if let Some(address) = addresses.iter().find(|address| !is_public(address.ip())){
+                return Err(format!("{} resolves to {}, which is not a public address", name.as_str(), address.ip()).into());
+            }
+            let addresses: Addrs = Box::new(addresses.into_iter());
+            Ok(addresses)
+        })
+    }
+}
+
+/// Client for the deliveries. Unless `allow_private_targets` is set, it only connects to public addresses and doesn't
+/// follow redirects or use a proxy, which could lead elsewhere.
+pub fn client(allow_private_targets: bool) -> reqwest::Client{
+    let builder = reqwest::Client::builder();
+    let builder = if allow_private_targets{
+        builder
+    }else{
+        builder.dns_resolver(Arc::new(PublicOnlyResolver)).redirect(reqwest::redirect::Policy::none()).no_proxy()
+    };
+    builder.build().expect("the client configuration is valid")
+}
+
+/// Body of a delivery.
+#[derive(Serialize, Debug, Clone)]
+pub struct WebhookPayload{
+    pub webhook_id: String,
+    pub sent_at: DateTime<Utc>,
+    pub lieferengpaesse: Vec<Change<Lieferengpass>>,
+    pub briefe: Vec<Change<Brief>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy{
+    pub max_attempts: u32,
+    /// Wait time after the first failed attempt, doubled after each further one.
+    pub initial_backoff: Duration,
+}
+
+fn random_token(bytes: usize) -> String{
+    let mut rng = rand::thread_rng();
+    (0..bytes).map(|_| format!("{:02x}", rng.r#gen::<u8>())).collect()
+}
+
+/// Signature sent in the [`SIGNATURE_HEADER`] header: `sha256=` followed by the hex HMAC-SHA256 of the body.
+pub fn sign(secret: &str, body: &[u8]) -> String{
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(body);
+    let signature: String = mac.finalize().into_bytes().iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("sha256={}", signature)
+}
+
+/// Picks the changes matching the webhook's filter. Returns `None` if nothing matches.
+pub fn payload_for(webhook: &Webhook, changes: &ChangeSet) -> Option<WebhookPayload>{
+    let lieferengpaesse: Vec<Change<Lieferengpass>> = changes.lieferengpaesse.iter()
+        .filter(|change| change.record.as_ref().is_some_and(|record| webhook.filter.matches_lieferengpass(record)))
+        .cloned()
+        .collect();
+    let briefe: Vec<Change<Brief>> = changes.briefe.iter()
+        .filter(|change| match &change.record{
+            Some(record) => webhook.filter.matches_brief(record),
+            // Removed letters are no longer stored
+            None => webhook.filter.matches_all_briefe(),
+        })
+        .cloned()
+        .map(Change::without_full_text)
+        .collect();
+
+    if lieferengpaesse.is_empty() && briefe.is_empty(){
+        return None;
+    }
+
+    Some(WebhookPayload{
+        webhook_id: webhook.id.clone(),
+        sent_at: Utc::now(),
+        lieferengpaesse,
+        briefe,
+    })
+}
+
+/// POSTs the signed payload to the webhook, retrying with exponential backoff until it answers with a 2xx status.
+pub async fn deliver(client: &reqwest::Client, webhook: &Webhook, payload: &WebhookPayload, policy: RetryPolicy) -> Result<(), String>{
+    let body = serde_json::to_vec(payload).map_err(|e| e.to_string())?;
+    let signature = sign(&webhook.secret, &body);
+
+    let mut backoff = policy.initial_backoff;
+    let mut last_error = String::new();
+    for attempt in 1..=policy.max_attempts.max(1){
+        let result = client.post(&webhook.url)
+            .header("Content-Type", "application/json")
+            .header(SIGNATURE_HEADER, &signature)
+            .timeout(Duration::from_secs(30))
+            .body(body.clone())
+            .send()
+            .await;
+
+        match result{
+            Ok(response) if response.status().is_success() => return Ok(()),
+            Ok(response) => last_error = format!("status {}", response.status()),
+            Err(e) => last_error = e.to_string(),
+        }
+
+        if attempt < policy.max_attempts{
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+
+    Err(last_error)
+}
+
+/// Sends the changes after `since` to all matching webhooks in the background.
+pub async fn dispatch(storage: Arc<TempStorage>, since: DateTime<Utc>) -> DateTime<Utc>{
+    let (changes, webhooks) = {
+        let handle = storage.storage.read().await;
+        (crate::changes::since(&handle, since), handle.webhooks.clone())
+    };
+    let allow_private_targets = storage.config.webhook_allow_private_targets;
+    let client = client(allow_private_targets);
+    let policy = RetryPolicy{
+        max_attempts: storage.config.webhook_max_attempts,
+        initial_backoff: Duration::from_secs(storage.config.webhook_backoff_seconds),
+    };
+
+    for webhook in webhooks{
+        if let Some(payload) = payload_for(&webhook, &changes){
+            let client = client.clone();
+            tokio::task::spawn(async move {
+                // The resolver only sees host names, addresses in the URL are checked here
+                if !allow_private_targets && let Ok(url) = Url::parse(&webhook.url) && let Err(e) = check_target(&url).await{
+                    tracing::warn!(webhook_id = %webhook.id, url = %webhook.url, error = %e, "Not delivering webhook");
+                    return;
+                }
+                if let Err(e) = deliver(&client, &webhook, &payload, policy).await{
+                    tracing::warn!(webhook_id = %webhook.id, url = %webhook.url, error = %e, "Failed to deliver webhook");
+                }
+            });
+        }
+    }
+
+    changes.until
+}
@@ -0,0 +1,243 @@
+use std::sync::LazyLock;
+use regex::{Regex, RegexSet};
+use crate::InnerStorage;
+
+/// Dictionary used for PEI letters unless `pei_wirkstoffe` is configured.
+const BUNDLED_DICTIONARY: &str = include_str!("../data/pei_wirkstoffe.txt");
+
+/// Strengths like "500 mg", "0,5 mg/ml", "5000 I.E.", "100 E/ml" or "2 %".
+static STRENGTH: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)\d+(?:[.,]\d+)?\s*(?:(?:mg|µg|μg|mcg|ug|g|ml|l|ie|iu|mmol|mikrogramm|milligramm|gramm|einheiten|e)\b|i\.\s?e\.|%)(?:\s*/\s*(?:\d+(?:[.,]\d+)?\s*)?(?:ml|l|g|mg|h|stunde|dosis|hub|tablette|ampulle)\b)?").unwrap()
+});
+
+static PARENTHESES: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\([^)]*\)").unwrap());
+
+/// Salt forms and hydrates that are not part of the Wirkstoff name. Compound forms come before their parts.
+const SALTS: &[&str] = &[
+    "dihydrogenphosphat", "hydrogenfumarat", "hydrogentartrat", "hydrogenmaleat", "hydrogensulfat", "dihydrochlorid",
+    "sesquihydrat", "hydrochlorid", "napadisilat", "dipropionat", "pentahydrat", "hexahydrat", "monohydrat", "hydrobromid",
+    "hemihydrat", "propionat", "magnesium", "trihydrat", "dinatrium", "wasserfrei", "succinat", "dihydrat", "gluconat",
+    "phosphat", "fumarat", "besilat", "besylat", "mesilat", "mesylat", "tosilat", "embonat", "tartrat", "calcium",
+    "natrium", "acetat", "citrat", "maleat", "lactat", "sulfat", "furoat", "kalium",
+];
+
+/// A salt suffix is only stripped from a compound word if at least this many characters remain.
+const MIN_BASE_LENGTH: usize = 5;
+
+/// Words starting with a cation name the salt itself, e.g. "Natriumpicosulfat", and are kept as they are.
+const CATIONS: &[&str] = &["natrium", "kalium", "calcium", "magnesium"];
+
+/// Length of the separator between two Wirkstoffe starting at `index`, if any.
+fn separator_at(text: &str, index: usize) -> Option<usize>{
+    let rest = &text[index..];
+    [",", ";", "/", "+", " und ", " & "].into_iter().find(|separator| rest.starts_with(separator)).map(str::len)
+}
+
+/// Splits a Wirkstoff list into its components, keeping their spelling. Commas and slashes inside
+/// strengths like "0,5 mg/ml" don't split.
+pub fn split(text: &str) -> Vec<String>{
+    let strengths: Vec<(usize, usize)> = STRENGTH.find_iter(text).map(|found| (found.start(), found.end())).collect();
+
+    let mut components = Vec::new();
+    let mut start = 0;
+    for (index, _) in text.char_indices(){
+        if index < start || strengths.iter().any(|(from, to)| (*from..*to).contains(&index)){
+            continue;
+        }
+        if let Some(length) = separator_at(text, index){
+            components.push(&text[start..index]);
+            start = index + length;
+        }
+    }
+    components.push(&text[start..]);
+
+    components.into_iter()
+        .map(|component| component.split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|component| !component.is_empty())
+        .collect()
+}
+
+fn strip_salt(word: &str) -> &str{
+    if CATIONS.iter().any(|cation| word.starts_with(cation)){
+        return word;
+    }
+    SALTS.iter()
+        .filter_map(|salt| word.strip_suffix(salt))
+        .find(|base| base.chars().count() >= MIN_BASE_LENGTH)
+        .unwrap_or(word)
+}
+
+/// Normalizes a single Wirkstoff: case-folded, without strengths, notes in parentheses and salt forms.
+/// "Amoxicillin-Trihydrat" and "Amoxicillin 500 mg" both become "amoxicillin".
+pub fn normalize_name(name: &str) -> Option<String>{
+    let name = name.to_lowercase();
+    let name = PARENTHESES.replace_all(&name, " ");
+    let name = STRENGTH.replace_all(&name, " ");
+
+    let words: Vec<&str> = name.split([' ', '-'])
+        .map(|word| word.trim_matches(|c: char| !c.is_alphanumeric()))
+        .filter(|word| !word.is_empty() && !word.chars().all(|c| c.is_ascii_digit()) && *word != "h2o")
+        .collect();
+
+    let mut base: Vec<&str> = words.iter().copied().filter(|word| !SALTS.contains(word)).collect();
+    if base.is_empty(){
+        // The Wirkstoff is a salt itself, e.g. "Natrium"
+        base = words;
+    }
+
+    let normalized = base.into_iter().map(strip_salt).collect::<Vec<_>>().join(" ");
+    if normalized.is_empty() { None } else { Some(normalized) }
+}
+
+fn normalize_all<'a>(texts: impl IntoIterator<Item = &'a str>) -> Vec<String>{
+    let mut normalized: Vec<String> = Vec::new();
+    for name in texts.into_iter().flat_map(split).filter_map(|component| normalize_name(&component)){
+        if !normalized.contains(&name){
+            normalized.push(name);
+        }
+    }
+    normalized
+}
+
+/// Splits a Wirkstoff list and normalizes every component, without duplicates.
+pub fn normalize(text: &str) -> Vec<String>{
+    normalize_all([text])
+}
+
+/// Sets `wirkstoffe_normalized` of all Lieferengpässe and letters.
+pub fn update(storage: &mut InnerStorage){
+    for entry in storage.lieferengpaesse.iter_mut(){
+        entry.wirkstoffe_normalized = normalize(&entry.wirkstoffe);
+    }
+    for brief in storage.briefe.values_mut(){
+        brief.wirkstoffe_normalized = normalize_all(brief.wirkstoffe.iter().flatten().map(String::as_str));
+    }
+}
+
+/// Known Wirkstoffe and their aliases, to find them in free text.
+///
+/// One entry per line, `Wirkstoff = Alias, Alias`. Lines starting with `#` are comments.
+pub struct WirkstoffDictionary{
+    wirkstoffe: Vec<String>,
+    /// One pattern per Wirkstoff, matching it and its aliases.
+    patterns: RegexSet,
+}
+
+impl WirkstoffDictionary{
+    pub fn parse(text: &str) -> Result<Self, regex::Error>{
+        let mut wirkstoffe = Vec::new();
+        let mut patterns = Vec::new();
+        for line in text.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')){
+            let (wirkstoff, aliases) = line.split_once('=').unwrap_or((line, ""));
+            let wirkstoff = wirkstoff.trim();
+            let terms: Vec<String> = std::iter::once(wirkstoff)
+                .chain(aliases.split(',').map(str::trim))
+                .filter(|term| !term.is_empty())
+                .map(regex::escape)
+                .collect();
+            wirkstoffe.push(wirkstoff.to_string());
+            patterns.push(format!(r"(?i)\b(?:{})", terms.join("|")));
+        }
+        Ok(WirkstoffDictionary{ wirkstoffe, patterns: RegexSet::new(patterns)? })
+    }
+
+    pub fn bundled() -> Self{
+        Self::parse(BUNDLED_DICTIONARY).expect("bundled dictionary is valid")
+    }
+
+    /// Reads the dictionary from `path`, or uses the bundled one without a path or if the file can't be read.
+    pub fn load(path: Option<&str>) -> Self{
+        let Some(path) = path else { return Self::bundled() };
+        let parsed = std::fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|text| Self::parse(&text).map_err(|e| e.to_string()));
+        match parsed{
+            Ok(dictionary) => dictionary,
+            Err(e) => {
+                tracing::warn!(path, error = %e, "Failed to load Wirkstoff dictionary, using the bundled one");
+                Self::bundled()
+            }
+        }
+    }
+
+    /// Wirkstoffe mentioned in the text, in dictionary order.
+    pub fn find(&self, text: &str) -> Vec<String>{
+        self.patterns.matches(text).into_iter().map(|index| self.wirkstoffe[index].clone()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn splits_combinations_but_not_strengths(){
+        for (text, components) in [
+            ("Amoxicillin", vec!["Amoxicillin"]),
+            ("Amoxicillin-Trihydrat, Clavulansäure", vec!["Amoxicillin-Trihydrat", "Clavulansäure"]),
+            ("Amlodipin/Valsartan/Hydrochlorothiazid", vec!["Amlodipin", "Valsartan", "Hydrochlorothiazid"]),
+            ("Ezetimib + Simvastatin", vec!["Ezetimib", "Simvastatin"]),
+            ("Sulfamethoxazol und Trimethoprim", vec!["Sulfamethoxazol", "Trimethoprim"]),
+            ("Lidocain; Prilocain", vec!["Lidocain", "Prilocain"]),
+            ("Salbutamol 0,1 mg/Hub", vec!["Salbutamol 0,1 mg/Hub"]),
+            ("Fentanyl 12,5 µg/h, Naloxon", vec!["Fentanyl 12,5 µg/h", "Naloxon"]),
+            ("  Metamizol-Natrium   500 mg ", vec!["Metamizol-Natrium 500 mg"]),
+            ("", vec![]),
+        ]{
+            assert_eq!(split(text), components, "{text}");
+        }
+    }
+
+    #[test]
+    fn normalizes_salts_strengths_and_notes(){
+        for (name, normalized) in [
+            ("Amoxicillin-Trihydrat", Some("amoxicillin")),
+            ("Amoxicillin 500 mg", Some("amoxicillin")),
+            ("AMOXICILLIN", Some("amoxicillin")),
+            ("Metamizol-Natrium 500 mg", Some("metamizol")),
+            ("Metamizol-Natrium-Monohydrat", Some("metamizol")),
+            ("Salbutamolsulfat", Some("salbutamol")),
+            ("Salbutamol 0,1 mg/Hub", Some("salbutamol")),
+            ("Epinephrin (als Hydrogentartrat) 0,3 mg", Some("epinephrin")),
+            ("Insulin glargin (gentechnisch hergestellt) 100 E/ml", Some("insulin glargin")),
+            ("Colecalciferol 20000 I.E.", Some("colecalciferol")),
+            ("Natriumpicosulfat", Some("natriumpicosulfat")),
+            ("Natrium", Some("natrium")),
+            // Too short to be a compound with a salt suffix
+            ("Acetat", Some("acetat")),
+            ("500 mg", None),
+        ]{
+            assert_eq!(normalize_name(name).as_deref(), normalized, "{name}");
+        }
+    }
+
+    #[test]
+    fn normalizes_combination_products_without_duplicates(){
+        assert_eq!(normalize("Amoxicillin-Trihydrat, Clavulansäure (als Kaliumsalz)"), ["amoxicillin", "clavulansäure"]);
+        assert_eq!(normalize("Amoxicillin 250 mg / Amoxicillin-Trihydrat"), ["amoxicillin"]);
+        assert_eq!(normalize_all(["Ezetimib + Simvastatin", "Simvastatin 20 mg"]), ["ezetimib", "simvastatin"]);
+    }
+
+    #[test]
+    fn finds_dictionary_entries_at_word_boundaries(){
+        let dictionary = WirkstoffDictionary::parse("# Comment\nTozinameran = Comirnaty, BNT162b2\nHepatitis-B-Impfstoff = Engerix-B\n\nHumanalbumin\n").unwrap();
+
+        for (text, found) in [
+            ("Rote-Hand-Brief zu Comirnaty", vec!["Tozinameran"]),
+            ("Impfstoff BNT162b2 (Tozinameran)", vec!["Tozinameran"]),
+            ("comirnaty und engerix-b", vec!["Tozinameran", "Hepatitis-B-Impfstoff"]),
+            ("Humanalbumin-Lösungen", vec!["Humanalbumin"]),
+            // Only at the start of a word
+            ("XComirnaty", vec![]),
+            ("Nicht-Humanalbumin", vec!["Humanalbumin"]),
+            ("Serumalbumin", vec![]),
+        ]{
+            assert_eq!(dictionary.find(text), found, "{text}");
+        }
+    }
+
+    #[test]
+    fn parses_the_bundled_dictionary(){
+        assert!(WirkstoffDictionary::bundled().find("Shingrix").contains(&"Herpes-zoster-Impfstoff".to_string()));
+    }
+}
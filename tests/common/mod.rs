@@ -0,0 +1,121 @@
+//! Minimal HTTP/1.1 server standing in for upstream sites and webhook receivers.
+
+// Every test file compiles its own copy, not all of them use everything
+#![allow(dead_code)]
+
+use std::sync::{Arc, Mutex};
+use rocket::tokio;
+use rocket::tokio::io::{AsyncReadExt, AsyncWriteExt};
+use rocket::tokio::net::{TcpListener, TcpStream};
+
+#[derive(Debug, Clone)]
+pub struct Request{
+    pub method: String,
+    /// Path including the query string.
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request{
+    pub fn header(&self, name: &str) -> Option<&str>{
+        self.headers.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+    }
+}
+
+pub struct Response{
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Response{
+    pub fn new(status: u16, content_type: &'static str, body: impl Into<Vec<u8>>) -> Self{
+        Response{ status, content_type, body: body.into() }
+    }
+}
+
+pub struct StandIn{
+    /// Base URL without trailing slash, e.g. `http://127.0.0.1:41234`.
+    pub url: String,
+    pub requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl StandIn{
+    pub fn requests(&self) -> Vec<Request>{
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+/// Starts the server on a free port. `handler` gets every request together with the number of requests received before it.
+pub async fn serve<F>(handler: F) -> StandIn where F: Fn(&Request, usize) -> Response + Send + Sync + 'static{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let requests: Arc<Mutex<Vec<Request>>> = Arc::default();
+    let handler = Arc::new(handler);
+
+    let recorded = requests.clone();
+    tokio::spawn(async move {
+        loop{
+            let Ok((stream, _)) = listener.accept().await else { return };
+            let recorded = recorded.clone();
+            let handler = handler.clone();
+            tokio::spawn(async move {
+                handle(stream, recorded, handler).await;
+            });
+        }
+    });
+
+    StandIn{ url, requests }
+}
+
+async fn handle<F>(mut stream: TcpStream, recorded: Arc<Mutex<Vec<Request>>>, handler: Arc<F>) where F: Fn(&Request, usize) -> Response{
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 4096];
+
+    let header_end = loop{
+        let read = stream.read(&mut chunk).await.unwrap_or(0);
+        if read == 0{
+            return;
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+        if let Some(position) = buffer.windows(4).position(|window| window == b"\r\n\r\n"){
+            break position + 4;
+        }
+    };
+
+    let head = String::from_utf8_lossy(&buffer[..header_end]).to_string();
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let method = request_line.next().unwrap_or_default().to_string();
+    let path = request_line.next().unwrap_or_default().to_string();
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .collect();
+
+    let content_length: usize = headers.iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.parse().ok())
+        .unwrap_or(0);
+    while buffer.len() < header_end + content_length{
+        let read = stream.read(&mut chunk).await.unwrap_or(0);
+        if read == 0{
+            break;
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    }
+
+    let request = Request{ method, path, headers, body: buffer[header_end..].to_vec() };
+    let response = {
+        let mut recorded = recorded.lock().unwrap();
+        let response = handler(&request, recorded.len());
+        recorded.push(request);
+        response
+    };
+
+    let head = format!("HTTP/1.1 {} Stand-In\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", response.status, response.content_type, response.body.len());
+    let _ = stream.write_all(head.as_bytes()).await;
+    let _ = stream.write_all(&response.body).await;
+    let _ = stream.shutdown().await;
+}
@@ -0,0 +1,526 @@
+mod common;
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use chrono::{Duration, NaiveDate, Utc};
+use medihelp_api::changes::ChangeKind;
+use medihelp_api::config::AppConfig;
+use medihelp_api::lieferengpaesse::{self, ArtDesGrundes, InfoAnFachkreise, Klassifikation, LieferengpassStatus, Meldungsart};
+use medihelp_api::rote_hand_briefe::{self, Brief, LetterType};
+use medihelp_api::{persistence, TempStorage};
+use common::{Response, StandIn};
+
+const HTML: &str = "text/html; charset=utf-8";
+/// PharmNet sends the CSV in Windows-1252 without declaring a charset.
+const CSV: &str = "text/csv";
+const LIEFERENGPAESSE_CSV: &[u8] = include_bytes!("fixtures/lieferengpaesse.csv");
+
+/// Storage with every upstream pointed at the stand-in server.
+fn storage(upstream: &StandIn) -> Arc<TempStorage>{
+    storage_with(upstream, serde_json::json!({}))
+}
+
+/// Like [`storage`], with further settings.
+fn storage_with(upstream: &StandIn, settings: serde_json::Value) -> Arc<TempStorage>{
+    let mut config = serde_json::json!({
+        "persistence": "memory",
+        "bfarm_base_url": upstream.url,
+        "pei_base_url": upstream.url,
+        "pharmnet_base_url": upstream.url,
+    });
+    config.as_object_mut().unwrap().extend(settings.as_object().unwrap().clone());
+    let config: AppConfig = serde_json::from_value(config).unwrap();
+    let persistence = persistence::open(&config).unwrap();
+    Arc::new(TempStorage::load(config, persistence))
+}
+
+async fn serve_lieferengpaesse_csv() -> StandIn{
+    common::serve(|request, _| {
+        if request.path == "/lieferengpassmeldungen/public/csv"{
+            Response::new(200, CSV, LIEFERENGPAESSE_CSV)
+        }else{
+            not_found()
+        }
+    }).await
+}
+
+fn not_found() -> Response{
+    Response::new(404, HTML, "<html><body>Nicht gefunden</body></html>")
+}
+
+async fn briefe_by_title(storage: &TempStorage) -> Vec<Brief>{
+    let mut briefe: Vec<Brief> = storage.storage.read().await.briefe.values().cloned().collect();
+    briefe.sort_by(|a, b| a.title.cmp(&b.title));
+    briefe
+}
+
+/// The BfArM listing without the row of the letter dated `date`.
+fn without_row(list: &str, date: &str) -> String{
+    let start = list.find(&format!("<tr>\n      <td>{}</td>", date)).unwrap();
+    let end = start + list[start..].find("</tr>").unwrap() + "</tr>".len();
+    format!("{}{}", &list[..start], &list[end..])
+}
+
+#[rocket::async_test]
+async fn crawls_bfarm_letters(){
+    let upstream = common::serve(|request, _| {
+        if request.path.starts_with("/DE/Arzneimittel/Pharmakovigilanz/Risikoinformationen/Rote-Hand-Briefe/_node.html"){
+            if request.path.ends_with("list%253D1"){
+                Response::new(200, HTML, include_str!("fixtures/bfarm_list.html"))
+            }else{
+                Response::new(200, HTML, include_str!("fixtures/bfarm_empty.html"))
+            }
+        }else if request.path.starts_with("/SharedDocs/Risikoinformationen/"){
+            Response::new(200, HTML, include_str!("fixtures/bfarm_letter.html"))
+        }else{
+            not_found()
+        }
+    }).await;
+    let storage = storage(&upstream);
+
+    let report = rote_hand_briefe::crawl_bfarm(storage.clone()).await.unwrap();
+    assert_eq!(report.parsed, 2);
+    assert_eq!(report.skipped, BTreeMap::from([("invalid date".to_string(), 1)]));
+
+    let briefe = briefe_by_title(&storage).await;
+    assert_eq!(briefe.len(), 2, "the row without a valid date is skipped");
+
+    let metamizol = &briefe[0];
+    assert_eq!(metamizol.title, "Informationsbrief zu Metamizol");
+    assert_eq!(metamizol.letter_type, LetterType::Informationsbrief);
+    assert_eq!(metamizol.date, NaiveDate::from_ymd_opt(2025, 2, 2).unwrap());
+    assert_eq!(metamizol.wirkstoffe, Some(vec!["Metamizol-Natrium 500 mg".to_string()]));
+
+    let amoxicillin = &briefe[1];
+    let link = format!("{}/SharedDocs/Risikoinformationen/Pharmakovigilanz/DE/RHB/2025/rhb-amoxicillin", upstream.url);
+    assert_eq!(amoxicillin.title, "Rote-Hand-Brief zu Amoxicillin: Lieferengpass bei Trockensaft");
+    assert_eq!(amoxicillin.letter_type, LetterType::RoteHandBrief);
+    assert_eq!(amoxicillin.date, NaiveDate::from_ymd_opt(2025, 3, 14).unwrap());
+    assert_eq!(amoxicillin.wirkstoffe, Some(vec!["Amoxicillin-Trihydrat".to_string(), "Clavulansäure".to_string()]));
+    assert_eq!(amoxicillin.short_description.as_deref(), Some("Einschränkung der Verfügbarkeit von Amoxicillin-Trockensaft."));
+    assert_eq!(amoxicillin.long_description.as_deref(), Some("Die Zulassungsinhaber informieren in Abstimmung mit dem BfArM über eine eingeschränkte Verfügbarkeit."));
+    assert_eq!(amoxicillin.link_to_pdf, format!("{}?__blob=publicationFile", link));
+    assert_eq!(amoxicillin.id, rote_hand_briefe::letter_id(&link));
+    assert_eq!(amoxicillin.link_to_html, link);
+
+    assert!(storage.storage.read().await.briefe_refreshed_at.is_some());
+}
+
+#[rocket::async_test]
+async fn crawls_pei_letters(){
+    let upstream = common::serve(|request, _| {
+        if request.path.starts_with("/SiteGlobals/Forms/Suche/Sicherheitsinformationsuche_Formular.html"){
+            if request.path.contains("list%253D1&"){
+                Response::new(200, HTML, include_str!("fixtures/pei_search.html"))
+            }else{
+                Response::new(200, HTML, include_str!("fixtures/pei_empty.html"))
+            }
+        }else if request.path.starts_with("/SharedDocs/Arzneimittelsicherheit/rhb/"){
+            Response::new(200, HTML, include_str!("fixtures/pei_letter_masern.html"))
+        }else if request.path.starts_with("/SharedDocs/Arzneimittelsicherheit/info/"){
+            Response::new(200, HTML, include_str!("fixtures/pei_letter_albumin.html"))
+        }else{
+            not_found()
+        }
+    }).await;
+    let storage = storage(&upstream);
+
+    let report = rote_hand_briefe::crawl_pei(storage.clone()).await.unwrap();
+    assert_eq!(report.parsed, 2);
+    assert!(report.skipped.is_empty());
+
+    let briefe = briefe_by_title(&storage).await;
+    assert_eq!(briefe.len(), 2);
+    assert!(upstream.requests().iter().all(|request| !request.path.contains("sonstiges")), "results that aren't letters are not crawled");
+
+    let albumin = &briefe[0];
+    assert_eq!(albumin.title, "Informationsbrief zu Humanalbumin");
+    assert_eq!(albumin.letter_type, LetterType::Informationsbrief);
+    assert_eq!(albumin.date, NaiveDate::from_ymd_opt(2025, 2, 5).unwrap(), "falls back to the update date");
+
+    let masern = &briefe[1];
+    assert_eq!(masern.title, "Rote-Hand-Brief zu Masern-Impfstoff");
+    assert_eq!(masern.letter_type, LetterType::RoteHandBrief);
+    assert_eq!(masern.date, NaiveDate::from_ymd_opt(2025, 1, 21).unwrap());
+    assert_eq!(masern.short_description.as_deref(), Some("Fehlerhafte Chargen des Masern-Impfstoffs werden zurückgerufen."));
+    assert_eq!(masern.link_to_html, format!("{}/SharedDocs/Arzneimittelsicherheit/rhb/2025/rhb-masernimpfstoff.html", upstream.url));
+    assert_eq!(masern.link_to_pdf, format!("{}/SharedDocs/Downloads/DE/rhb/2025/rhb-masernimpfstoff.pdf", upstream.url));
+    assert_eq!(masern.wirkstoffe, None);
+}
+
+#[rocket::async_test]
+async fn reports_changed_and_unlisted_bfarm_letters(){
+    let recrawl = Arc::new(AtomicBool::new(false));
+    let upstream = common::serve({
+        let recrawl = recrawl.clone();
+        move |request, _| {
+            if request.path.starts_with("/DE/Arzneimittel/Pharmakovigilanz/Risikoinformationen/Rote-Hand-Briefe/_node.html"){
+                if !request.path.ends_with("list%253D1"){
+                    return Response::new(200, HTML, include_str!("fixtures/bfarm_empty.html"));
+                }
+                let list = include_str!("fixtures/bfarm_list.html");
+                if !recrawl.load(Ordering::SeqCst){
+                    return Response::new(200, HTML, list);
+                }
+                // Amoxicillin gets a new title, Metamizol is withdrawn
+                let list = without_row(list, "02.02.2025").replace("Lieferengpass bei Trockensaft", "Lieferengpass bei Trockensaft beendet");
+                Response::new(200, HTML, list)
+            }else if request.path.starts_with("/SharedDocs/Risikoinformationen/"){
+                Response::new(200, HTML, include_str!("fixtures/bfarm_letter.html"))
+            }else{
+                not_found()
+            }
+        }
+    }).await;
+    let storage = storage(&upstream);
+
+    rote_hand_briefe::crawl_bfarm(storage.clone()).await.unwrap();
+    let metamizol_id = briefe_by_title(&storage).await[0].id.clone();
+    rote_hand_briefe::crawl_bfarm(storage.clone()).await.unwrap();
+    let unchanged = storage.storage.read().await.changes.len();
+    assert_eq!(unchanged, 2, "letters that didn't change aren't reported again");
+
+    recrawl.store(true, Ordering::SeqCst);
+    rote_hand_briefe::crawl_bfarm(storage.clone()).await.unwrap();
+
+    let briefe = briefe_by_title(&storage).await;
+    assert_eq!(briefe.len(), 1);
+    assert_eq!(briefe[0].title, "Rote-Hand-Brief zu Amoxicillin: Lieferengpass bei Trockensaft beendet");
+    assert!(briefe[0].long_description.is_some(), "the letter page is crawled again");
+
+    let handle = storage.storage.read().await;
+    let mut events: Vec<(String, ChangeKind)> = handle.changes[unchanged..].iter().map(|event| (event.id.clone(), event.kind)).collect();
+    events.sort_by_key(|(_, kind)| *kind as u8);
+    assert_eq!(events, vec![(briefe[0].id.clone(), ChangeKind::Modified), (metamizol_id, ChangeKind::Removed)]);
+}
+
+#[rocket::async_test]
+async fn keeps_bfarm_letters_when_the_listing_breaks_off(){
+    const LISTED: u8 = 0;
+    const SERVER_ERROR: u8 = 1;
+    const MAINTENANCE: u8 = 2;
+    let second_page = Arc::new(AtomicU8::new(LISTED));
+    let upstream = common::serve({
+        let second_page = second_page.clone();
+        move |request, _| {
+            if request.path.starts_with("/DE/Arzneimittel/Pharmakovigilanz/Risikoinformationen/Rote-Hand-Briefe/_node.html"){
+                // Amoxicillin is on the first page, Metamizol on the second
+                let list = include_str!("fixtures/bfarm_list.html");
+                if request.path.ends_with("list%253D1"){
+                    return Response::new(200, HTML, without_row(list, "02.02.2025"));
+                }
+                if !request.path.ends_with("list%253D2"){
+                    return Response::new(200, HTML, include_str!("fixtures/bfarm_empty.html"));
+                }
+                match second_page.load(Ordering::SeqCst){
+                    SERVER_ERROR => Response::new(500, HTML, "<html><body>Interner Fehler</body></html>"),
+                    MAINTENANCE => Response::new(200, HTML, "<html><body><h1>Wartungsarbeiten</h1></body></html>"),
+                    _ => Response::new(200, HTML, without_row(list, "14.03.2025")),
+                }
+            }else if request.path.starts_with("/SharedDocs/Risikoinformationen/"){
+                Response::new(200, HTML, include_str!("fixtures/bfarm_letter.html"))
+            }else{
+                not_found()
+            }
+        }
+    }).await;
+    let storage = storage(&upstream);
+
+    rote_hand_briefe::crawl_bfarm(storage.clone()).await.unwrap();
+    assert_eq!(briefe_by_title(&storage).await.len(), 2);
+    let listed = storage.storage.read().await.changes.len();
+
+    second_page.store(SERVER_ERROR, Ordering::SeqCst);
+    assert!(rote_hand_briefe::crawl_bfarm(storage.clone()).await.is_err());
+    assert_eq!(briefe_by_title(&storage).await.len(), 2, "the letters of the failed page are kept");
+
+    second_page.store(MAINTENANCE, Ordering::SeqCst);
+    rote_hand_briefe::crawl_bfarm(storage.clone()).await.unwrap();
+    assert_eq!(briefe_by_title(&storage).await.len(), 2, "the letters past an unexpected page are kept");
+    assert_eq!(storage.storage.read().await.changes.len(), listed, "no letter is reported as removed");
+}
+
+#[rocket::async_test]
+async fn reports_changed_and_unlisted_pei_letters(){
+    let recrawl = Arc::new(AtomicBool::new(false));
+    let upstream = common::serve({
+        let recrawl = recrawl.clone();
+        move |request, _| {
+            let recrawl = recrawl.load(Ordering::SeqCst);
+            if request.path.starts_with("/SiteGlobals/Forms/Suche/Sicherheitsinformationsuche_Formular.html"){
+                if !request.path.contains("list%253D1&"){
+                    return Response::new(200, HTML, include_str!("fixtures/pei_empty.html"));
+                }
+                let search = include_str!("fixtures/pei_search.html");
+                if recrawl{
+                    // Albumin is withdrawn
+                    let search: String = search.lines().filter(|line| !line.contains("info-albumin")).collect::<Vec<_>>().join("\n");
+                    return Response::new(200, HTML, search);
+                }
+                Response::new(200, HTML, search)
+            }else if request.path.starts_with("/SharedDocs/Arzneimittelsicherheit/rhb/"){
+                let letter = include_str!("fixtures/pei_letter_masern.html");
+                if recrawl{
+                    return Response::new(200, HTML, letter.replace("werden zurückgerufen", "wurden zurückgerufen"));
+                }
+                Response::new(200, HTML, letter)
+            }else if request.path.starts_with("/SharedDocs/Arzneimittelsicherheit/info/"){
+                Response::new(200, HTML, include_str!("fixtures/pei_letter_albumin.html"))
+            }else{
+                not_found()
+            }
+        }
+    }).await;
+    let storage = storage(&upstream);
+
+    rote_hand_briefe::crawl_pei(storage.clone()).await.unwrap();
+    let albumin_id = briefe_by_title(&storage).await[0].id.clone();
+    rote_hand_briefe::crawl_pei(storage.clone()).await.unwrap();
+    let unchanged = storage.storage.read().await.changes.len();
+    assert_eq!(unchanged, 2, "letters that didn't change aren't reported again");
+
+    recrawl.store(true, Ordering::SeqCst);
+    rote_hand_briefe::crawl_pei(storage.clone()).await.unwrap();
+
+    let briefe = briefe_by_title(&storage).await;
+    assert_eq!(briefe.len(), 1);
+    assert_eq!(briefe[0].short_description.as_deref(), Some("Fehlerhafte Chargen des Masern-Impfstoffs wurden zurückgerufen."));
+
+    let handle = storage.storage.read().await;
+    let mut events: Vec<(String, ChangeKind)> = handle.changes[unchanged..].iter().map(|event| (event.id.clone(), event.kind)).collect();
+    events.sort_by_key(|(_, kind)| *kind as u8);
+    assert_eq!(events, vec![(briefe[0].id.clone(), ChangeKind::Modified), (albumin_id, ChangeKind::Removed)]);
+}
+
+#[rocket::async_test]
+async fn crawls_known_pei_letters_again_in_turns(){
+    let upstream = common::serve(|request, _| {
+        if request.path.starts_with("/SiteGlobals/Forms/Suche/Sicherheitsinformationsuche_Formular.html"){
+            if request.path.contains("list%253D1&"){
+                Response::new(200, HTML, include_str!("fixtures/pei_search.html"))
+            }else{
+                Response::new(200, HTML, include_str!("fixtures/pei_empty.html"))
+            }
+        }else if request.path.starts_with("/SharedDocs/Arzneimittelsicherheit/rhb/"){
+            Response::new(200, HTML, include_str!("fixtures/pei_letter_masern.html"))
+        }else if request.path.starts_with("/SharedDocs/Arzneimittelsicherheit/info/"){
+            Response::new(200, HTML, include_str!("fixtures/pei_letter_albumin.html"))
+        }else{
+            not_found()
+        }
+    }).await;
+    let storage = storage_with(&upstream, serde_json::json!({ "pei_recheck_letters": 1 }));
+    let crawled = |upstream: &StandIn| -> Vec<String> {
+        upstream.requests().into_iter().map(|request| request.path).filter(|path| path.starts_with("/SharedDocs/")).collect()
+    };
+
+    rote_hand_briefe::crawl_pei(storage.clone()).await.unwrap();
+    assert_eq!(crawled(&upstream).len(), 2, "new letters are all crawled");
+
+    rote_hand_briefe::crawl_pei(storage.clone()).await.unwrap();
+    rote_hand_briefe::crawl_pei(storage.clone()).await.unwrap();
+    let rechecked = &crawled(&upstream)[2..];
+    assert_eq!(rechecked.len(), 2, "one known letter is crawled again per refresh");
+    assert_ne!(rechecked[0], rechecked[1], "the known letters take turns");
+    assert_eq!(briefe_by_title(&storage).await.len(), 2);
+}
+
+#[rocket::async_test]
+async fn refreshes_lieferengpaesse_from_csv(){
+    let upstream = serve_lieferengpaesse_csv().await;
+    let storage = storage(&upstream);
+
+    let report = lieferengpaesse::refresh_lieferengpaesse(storage.clone()).await.unwrap();
+    assert_eq!(report.parsed, 3);
+    assert_eq!(report.skipped.len(), 1);
+    assert!(report.skipped.keys().all(|reason| reason.contains("unknown variant `neu eingestuft`")), "{:?}", report.skipped);
+
+    let handle = storage.storage.read().await;
+    assert!(handle.lieferengpaesse_refreshed_at.is_some());
+    let mut bearbeitungsnummern: Vec<&str> = handle.lieferengpaesse.iter().map(|entry| entry.bearbeitungsnummer.as_str()).collect();
+    bearbeitungsnummern.sort();
+    assert_eq!(bearbeitungsnummern, ["2025-0001", "2025-0002", "2025-0003"], "the row with an unknown klassifikation is skipped");
+
+    let rejected = handle.lieferengpaesse_rejected.as_ref().unwrap();
+    assert_eq!(rejected.len(), 1);
+    assert_eq!(rejected[0].line, 5);
+    assert_eq!(rejected[0].bearbeitungsnummer.as_deref(), Some("2025-0004"));
+    assert!(rejected[0].raw.starts_with("5678901;56789;2025-0004;N/A;"), "{}", rejected[0].raw);
+    assert!(rejected[0].raw.ends_with(";neu eingestuft"), "{}", rejected[0].raw);
+    assert_eq!(rejected[0].error, "unknown variant `neu eingestuft` in klassifikation");
+
+    let amoxicillin = handle.lieferengpaesse.iter().find(|entry| entry.bearbeitungsnummer == "2025-0001").unwrap();
+    assert_eq!(amoxicillin.pzn, 2345678);
+    assert_eq!(amoxicillin.zulassungsinhaber, "Müller Pharma GmbH");
+    assert_eq!(amoxicillin.erstmeldung, None);
+    assert_eq!(amoxicillin.anmerkung_zum_grund, None);
+    assert_eq!(amoxicillin.alternativpraeparat.as_deref(), Some("Amoxicillin 250 mg Trockensaft"));
+    assert_eq!(amoxicillin.beginn, NaiveDate::from_ymd_opt(2025, 1, 15).unwrap());
+    assert!(amoxicillin.kkh_relevant);
+    assert_eq!(amoxicillin.status, LieferengpassStatus::Active);
+    assert_eq!(amoxicillin.wirkstoffe_normalized, ["amoxicillin"], "derived data is updated with the records");
+    assert!(handle.lieferengpaesse_version.is_some());
+
+    let metamizol = handle.lieferengpaesse.iter().find(|entry| entry.bearbeitungsnummer == "2025-0002").unwrap();
+    assert_eq!(metamizol.enr, [23456, 34567]);
+    assert_eq!(metamizol.meldungsart, Meldungsart::Aenderungsmeldung);
+    assert_eq!(metamizol.grund, "Erhöhte Nachfrage");
+    assert!(!metamizol.kkh_relevant);
+}
+
+#[rocket::async_test]
+async fn keeps_unknown_values_in_lenient_mode(){
+    let upstream = serve_lieferengpaesse_csv().await;
+    let storage = storage_with(&upstream, serde_json::json!({ "lieferengpaesse_parse_mode": "lenient" }));
+
+    let report = lieferengpaesse::refresh_lieferengpaesse(storage.clone()).await.unwrap();
+    assert_eq!(report.parsed, 4);
+    assert!(report.skipped.is_empty());
+
+    let handle = storage.storage.read().await;
+    assert_eq!(handle.lieferengpaesse_rejected.as_deref().map(<[_]>::len), Some(0));
+    let neu = handle.lieferengpaesse.iter().find(|entry| entry.bearbeitungsnummer == "2025-0004").unwrap();
+    assert_eq!(neu.klassifikation, Klassifikation::Other("neu eingestuft".to_string()));
+    assert_eq!(neu.unknown_values(), [("klassifikation", "neu eingestuft")]);
+    assert_eq!(serde_json::to_value(neu).unwrap()["klassifikation"], "neu eingestuft");
+    let stored: Klassifikation = serde_json::from_value(serde_json::json!("neu eingestuft")).unwrap();
+    assert_eq!(stored, neu.klassifikation, "read back the same way from storage");
+}
+
+#[rocket::async_test]
+async fn stores_unknown_values_that_look_like_json(){
+    let upstream = serve_lieferengpaesse_csv().await;
+    let storage = storage_with(&upstream, serde_json::json!({ "lieferengpaesse_parse_mode": "lenient", "persistence": "sqlite", "database": ":memory:" }));
+    lieferengpaesse::refresh_lieferengpaesse(storage.clone()).await.unwrap();
+
+    let mut entries = storage.storage.read().await.lieferengpaesse.clone();
+    let neu = entries.iter_mut().find(|entry| entry.bearbeitungsnummer == "2025-0004").unwrap();
+    neu.meldungsart = Meldungsart::Other("123".to_string());
+    neu.art_des_grundes = ArtDesGrundes::Other("null".to_string());
+    neu.info_an_fachkreise = InfoAnFachkreise::Other("[]".to_string());
+    neu.klassifikation = Klassifikation::Other("true".to_string());
+    let expected = serde_json::to_value(&*neu).unwrap();
+    storage.persistence.save_lieferengpaesse(&entries, None).unwrap();
+
+    let stored = storage.persistence.load().unwrap().lieferengpaesse;
+    let stored = serde_json::to_value(stored.iter().find(|entry| entry.bearbeitungsnummer == "2025-0004").unwrap()).unwrap();
+    for field in ["meldungsart", "art_des_grundes", "info_an_fachkreise", "klassifikation"]{
+        assert_eq!(stored[field], expected[field], "{field}");
+    }
+}
+
+/// Serves the CSV once in full, then without Ibuprofen (2025-0003).
+async fn serve_csv_without_ibuprofen() -> StandIn{
+    common::serve(|_, received| {
+        if received == 0{
+            return Response::new(200, CSV, LIEFERENGPAESSE_CSV);
+        }
+        let without_ibuprofen: Vec<&[u8]> = LIEFERENGPAESSE_CSV.split(|byte| *byte == b'\n')
+            .filter(|line| !line.windows(9).any(|window| window == b"2025-0003"))
+            .collect();
+        Response::new(200, CSV, without_ibuprofen.join(&b'\n'))
+    }).await
+}
+
+#[rocket::async_test]
+async fn marks_unlisted_lieferengpaesse_as_vanished(){
+    let upstream = serve_csv_without_ibuprofen().await;
+    let storage = storage(&upstream);
+
+    lieferengpaesse::refresh_lieferengpaesse(storage.clone()).await.unwrap();
+    lieferengpaesse::refresh_lieferengpaesse(storage.clone()).await.unwrap();
+
+    let handle = storage.storage.read().await;
+    assert_eq!(handle.lieferengpaesse.len(), 3);
+    let ibuprofen = handle.lieferengpaesse.iter().find(|entry| entry.bearbeitungsnummer == "2025-0003").unwrap();
+    assert_eq!(ibuprofen.status, LieferengpassStatus::Vanished);
+    assert!(ibuprofen.vanished_at.is_some());
+    assert!(handle.lieferengpaesse.iter().filter(|entry| entry.bearbeitungsnummer != "2025-0003").all(|entry| entry.status == LieferengpassStatus::Active));
+}
+
+#[rocket::async_test]
+async fn drops_vanished_lieferengpaesse_after_the_retention(){
+    let upstream = serve_csv_without_ibuprofen().await;
+    let storage = storage_with(&upstream, serde_json::json!({ "vanished_retention_days": 7 }));
+
+    lieferengpaesse::refresh_lieferengpaesse(storage.clone()).await.unwrap();
+    lieferengpaesse::refresh_lieferengpaesse(storage.clone()).await.unwrap();
+    {
+        let mut handle = storage.storage.write().await;
+        let ibuprofen = handle.lieferengpaesse.iter_mut().find(|entry| entry.bearbeitungsnummer == "2025-0003").unwrap();
+        ibuprofen.vanished_at = Some(Utc::now() - Duration::days(6));
+    }
+    lieferengpaesse::refresh_lieferengpaesse(storage.clone()).await.unwrap();
+    assert_eq!(storage.storage.read().await.lieferengpaesse.len(), 3, "kept within the retention");
+
+    {
+        let mut handle = storage.storage.write().await;
+        let ibuprofen = handle.lieferengpaesse.iter_mut().find(|entry| entry.bearbeitungsnummer == "2025-0003").unwrap();
+        ibuprofen.vanished_at = Some(Utc::now() - Duration::days(8));
+    }
+    lieferengpaesse::refresh_lieferengpaesse(storage.clone()).await.unwrap();
+    let handle = storage.storage.read().await;
+    assert!(handle.lieferengpaesse.iter().all(|entry| entry.bearbeitungsnummer != "2025-0003"));
+    assert!(handle.lieferengpaesse_history.contains_key("2025-0003"), "the history is kept");
+}
+
+#[rocket::async_test]
+async fn keeps_derived_fields_out_of_the_history(){
+    let upstream = common::serve(|_, _| {
+        // Ibuprofen is withdrawn by a Löschmeldung, in Windows-1252
+        let lines: Vec<Vec<u8>> = LIEFERENGPAESSE_CSV.split(|byte| *byte == b'\n')
+            .map(|line| {
+                let meldungsart = b";Erstmeldung;";
+                match line.windows(meldungsart.len()).position(|window| window == meldungsart){
+                    Some(start) if line.starts_with(b"4567890;") => [&line[..start], b";L\xf6schmeldung;".as_slice(), &line[start + meldungsart.len()..]].concat(),
+                    _ => line.to_vec(),
+                }
+            })
+            .collect();
+        Response::new(200, CSV, lines.join(&b'\n'))
+    }).await;
+    let storage = storage(&upstream);
+
+    lieferengpaesse::refresh_lieferengpaesse(storage.clone()).await.unwrap();
+
+    let handle = storage.storage.read().await;
+    let ibuprofen = handle.lieferengpaesse.iter().find(|entry| entry.bearbeitungsnummer == "2025-0003").unwrap();
+    assert_eq!(ibuprofen.status, LieferengpassStatus::Deleted);
+    let history = serde_json::to_value(&handle.lieferengpaesse_history["2025-0003"]).unwrap();
+    let record = &history["versions"][0]["record"];
+    assert_eq!(record["meldungsart"], "Löschmeldung");
+    assert!(record.get("status").is_none(), "the version doesn't claim a status: {record}");
+    assert!(record.get("related_briefe").is_none());
+}
+
+#[rocket::async_test]
+async fn persists_only_new_history_versions(){
+    let upstream = common::serve(|_, received| {
+        if received == 0{
+            return Response::new(200, CSV, LIEFERENGPAESSE_CSV);
+        }
+        // Ibuprofen is expected to be available a year earlier
+        let line = LIEFERENGPAESSE_CSV.windows(9).position(|window| window == b"2025-0003").unwrap();
+        let ende = line + LIEFERENGPAESSE_CSV[line..].windows(10).position(|window| window == b"31.12.2030").unwrap();
+        let mut changed = LIEFERENGPAESSE_CSV.to_vec();
+        changed[ende..ende + 10].copy_from_slice(b"31.12.2029");
+        Response::new(200, CSV, changed)
+    }).await;
+    let storage = storage_with(&upstream, serde_json::json!({ "persistence": "sqlite", "database": ":memory:" }));
+
+    for _ in 0..2{
+        lieferengpaesse::refresh_lieferengpaesse(storage.clone()).await.unwrap();
+        persistence::save_lieferengpaesse(storage.clone()).await;
+    }
+
+    let handle = storage.storage.read().await;
+    assert!(handle.lieferengpaesse_history.values().all(|history| history.unpersisted().versions.is_empty()));
+    let stored = storage.persistence.load().unwrap().lieferengpaesse_history;
+    assert_eq!(stored.len(), handle.lieferengpaesse_history.len());
+    for (bearbeitungsnummer, history) in &handle.lieferengpaesse_history{
+        assert_eq!(stored[bearbeitungsnummer].versions.len(), history.versions.len(), "{bearbeitungsnummer}");
+        assert_eq!(stored[bearbeitungsnummer].persisted_versions, history.versions.len());
+    }
+    assert_eq!(stored["2025-0003"].versions.len(), 2, "the changed record is a new version");
+}
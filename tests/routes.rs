@@ -0,0 +1,79 @@
+use std::sync::Arc;
+use medihelp_api::config::AppConfig;
+use medihelp_api::{mount, persistence, TempStorage};
+use rocket::http::{ContentType, Header, Status};
+use rocket::local::asynchronous::Client;
+
+async fn client() -> Client{
+    let config: AppConfig = serde_json::from_value(serde_json::json!({ "persistence": "memory" })).unwrap();
+    let persistence = persistence::open(&config).unwrap();
+    let storage = Arc::new(TempStorage::load(config, persistence));
+    storage.storage.write().await.lieferengpaesse_loaded_initially = true;
+    // Fails on colliding routes, like the launch would
+    Client::untracked(mount(rocket::build(), storage)).await.expect("routes should not collide")
+}
+
+#[rocket::async_test]
+async fn mounts_all_routes_without_collisions(){
+    let client = client().await;
+
+    let response = client.get("/api/status").dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    let response = client.get("/metrics").dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+}
+
+#[rocket::async_test]
+async fn routes_static_segments_before_bearbeitungsnummern(){
+    let client = client().await;
+
+    // Answered by the rejected rows route, the lookup would return 404 for an unknown Bearbeitungsnummer
+    let response = client.get("/api/lieferengpaesse/rejected").dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_string().await.unwrap(), "\"NotReady\"");
+
+    let response = client.get("/api/lieferengpaesse/pzn/1234567").dispatch().await;
+    assert_eq!(response.status(), Status::NotFound);
+    let response = client.get("/api/lieferengpaesse/pzn/history").dispatch().await;
+    assert_eq!(response.status(), Status::NotFound, "falls through to the history of Bearbeitungsnummer `pzn`");
+    let response = client.get("/api/lieferengpaesse/2025-0001/history").dispatch().await;
+    assert_eq!(response.status(), Status::NotFound);
+}
+
+#[rocket::async_test]
+async fn registers_webhooks_to_public_targets_only(){
+    let client = client().await;
+
+    let response = client.post("/api/webhooks").header(ContentType::JSON).body(r#"{"url": "http://127.0.0.1:9000/hook"}"#).dispatch().await;
+    assert_eq!(response.status(), Status::BadRequest);
+
+    let response = client.post("/api/webhooks").header(ContentType::JSON).body(r#"{"url": "https://93.184.215.14/hook"}"#).dispatch().await;
+    assert_eq!(response.status(), Status::Created);
+    let registration: serde_json::Value = response.into_json().await.unwrap();
+    let id = registration["Success"]["webhook"]["id"].as_str().unwrap();
+    let secret = registration["Success"]["secret"].as_str().unwrap();
+
+    let response = client.get(format!("/api/webhooks/{id}")).header(Header::new("X-Webhook-Secret", secret.to_string())).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    let wrong = format!("{}0", &secret[..secret.len() - 1]);
+    let response = client.get(format!("/api/webhooks/{id}")).header(Header::new("X-Webhook-Secret", wrong)).dispatch().await;
+    assert_eq!(response.status(), Status::NotFound);
+}
+
+#[rocket::async_test]
+async fn rejected_rows_answer_conditional_requests(){
+    let client = client().await;
+    {
+        let storage = client.rocket().state::<Arc<TempStorage>>().unwrap();
+        let mut handle = storage.storage.write().await;
+        handle.lieferengpaesse_rejected = Some(Vec::new());
+        handle.update_derived_data();
+    }
+
+    let response = client.get("/api/lieferengpaesse/rejected").dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    let etag = response.headers().get_one("ETag").expect("an ETag").to_string();
+
+    let response = client.get("/api/lieferengpaesse/rejected").header(Header::new("If-None-Match", etag)).dispatch().await;
+    assert_eq!(response.status(), Status::NotModified);
+}
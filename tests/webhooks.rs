@@ -0,0 +1,104 @@
+mod common;
+
+use std::time::Duration;
+use chrono::Utc;
+use medihelp_api::changes::{Change, ChangeKind, ChangeSet};
+use medihelp_api::rote_hand_briefe::LetterSource;
+use medihelp_api::webhooks::{self, RetryPolicy, Webhook, WebhookFilter, WebhookPayload};
+use common::Response;
+
+const FAST_RETRIES: RetryPolicy = RetryPolicy{
+    max_attempts: 3,
+    initial_backoff: Duration::from_millis(10),
+};
+
+fn empty_payload(webhook: &Webhook) -> WebhookPayload{
+    WebhookPayload{
+        webhook_id: webhook.id.clone(),
+        sent_at: Utc::now(),
+        lieferengpaesse: Vec::new(),
+        briefe: Vec::new(),
+    }
+}
+
+#[rocket::async_test]
+async fn delivers_signed_payload_after_retries(){
+    let receiver = common::serve(|_, received| {
+        if received < 2 { Response::new(500, "text/plain", "busy") } else { Response::new(204, "text/plain", "") }
+    }).await;
+
+    let webhook = Webhook::new(format!("{}/hook", receiver.url), WebhookFilter::default());
+    let result = webhooks::deliver(&reqwest::Client::new(), &webhook, &empty_payload(&webhook), FAST_RETRIES).await;
+    assert_eq!(result, Ok(()));
+
+    let requests = receiver.requests();
+    assert_eq!(requests.len(), 3);
+    for request in &requests{
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/hook");
+        assert_eq!(request.header(webhooks::SIGNATURE_HEADER), Some(webhooks::sign(&webhook.secret, &request.body).as_str()));
+    }
+
+    let body: serde_json::Value = serde_json::from_slice(&requests[2].body).unwrap();
+    assert_eq!(body["webhook_id"], webhook.id.as_str());
+}
+
+#[rocket::async_test]
+async fn gives_up_after_max_attempts(){
+    let receiver = common::serve(|_, _| Response::new(503, "text/plain", "down")).await;
+
+    let webhook = Webhook::new(receiver.url.clone(), WebhookFilter::default());
+    let result = webhooks::deliver(&reqwest::Client::new(), &webhook, &empty_payload(&webhook), FAST_RETRIES).await;
+
+    assert!(result.is_err());
+    assert_eq!(receiver.requests().len(), 3);
+}
+
+#[test]
+fn only_public_addresses_are_public(){
+    for (address, public) in [
+        ("93.184.215.14", true),
+        ("2606:2800:21f:cb07:6820:80da:af6b:8b2c", true),
+        ("127.0.0.1", false),
+        ("10.1.2.3", false),
+        ("172.16.0.1", false),
+        ("192.168.178.1", false),
+        ("169.254.169.254", false),
+        ("100.64.0.1", false),
+        ("0.0.0.0", false),
+        ("::1", false),
+        ("::ffff:127.0.0.1", false),
+        ("fd00::1", false),
+        ("fe80::1", false),
+    ]{
+        assert_eq!(webhooks::is_public(address.parse().unwrap()), public, "{address}");
+    }
+}
+
+#[rocket::async_test]
+async fn refuses_private_targets(){
+    for url in ["http://127.0.0.1:8000/hook", "http://[::1]/hook", "http://localhost/hook", "http://169.254.169.254/latest/meta-data"]{
+        assert!(webhooks::check_target(&url.parse().unwrap()).await.is_err(), "{url}");
+    }
+    assert_eq!(webhooks::check_target(&"https://93.184.215.14/hook".parse().unwrap()).await, Ok(()));
+
+    // A host name resolving to loopback, as after a changed DNS answer
+    let receiver = common::serve(|_, _| Response::new(204, "text/plain", "")).await;
+    let webhook = Webhook::new(receiver.url.replace("127.0.0.1", "localhost"), WebhookFilter::default());
+    let result = webhooks::deliver(&webhooks::client(false), &webhook, &empty_payload(&webhook), FAST_RETRIES).await;
+    assert!(result.is_err());
+    assert!(receiver.requests().is_empty());
+}
+
+#[test]
+fn sends_removed_letters_to_unfiltered_webhooks_only(){
+    let removed = Change{ id: "0123456789abcdef".to_string(), kind: ChangeKind::Removed, changed_at: Utc::now(), record: None };
+    let changes = ChangeSet{ since: Utc::now(), until: Utc::now(), complete: true, lieferengpaesse: Vec::new(), briefe: vec![removed] };
+
+    let unfiltered = Webhook::new("https://example.org/hook".to_string(), WebhookFilter::default());
+    let payload = webhooks::payload_for(&unfiltered, &changes).expect("a payload");
+    assert_eq!(payload.briefe.len(), 1);
+
+    let filtered = Webhook::new("https://example.org/hook".to_string(), WebhookFilter{ source: Some(LetterSource::PEI), ..WebhookFilter::default() });
+    assert!(webhooks::payload_for(&filtered, &changes).is_none());
+}